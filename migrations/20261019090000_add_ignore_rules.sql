@@ -0,0 +1 @@
+alter table data.entity add column ignore_rules text[] not null default '{}';
@@ -0,0 +1,30 @@
+-- Key paths of a JSON value, depth-first with sibling keys sorted the same way robserver does
+create function data.key_paths(obj jsonb, prefix text default null) returns text[]
+language plpgsql immutable as $$
+declare
+	result text[] := '{}';
+	k text;
+	path text;
+begin
+	if jsonb_typeof(obj) is distinct from 'object' then
+		return result;
+	end if;
+	for k in select key from jsonb_object_keys(obj) as key order by key collate "C" loop
+		path := case when prefix is null then k else prefix || '.' || k end;
+		result := result || path || data.key_paths(obj -> k, path);
+	end loop;
+	return result;
+end
+$$;
+
+alter table data.entity add column key_paths text[] not null default '{}';
+alter table data.entity add column key_digest uuid;
+alter table data.entity add column collision boolean not null default false;
+
+-- Shapes stored with ignore rules in effect will have the ignored keys included here
+update data.entity set key_paths = data.key_paths(payload);
+update data.entity set key_digest = md5(array_to_string(key_paths, E'\n'))::uuid;
+
+alter table data.entity alter column key_digest set not null;
+alter table data.entity drop constraint entity_pkey;
+alter table data.entity add constraint entity_pkey primary key (id, key_digest, vhost, exchange);
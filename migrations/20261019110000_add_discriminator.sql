@@ -0,0 +1,4 @@
+alter table data.entity add column discriminator text not null default '';
+
+alter table data.entity drop constraint entity_pkey;
+alter table data.entity add constraint entity_pkey primary key (id, key_digest, vhost, exchange, discriminator);
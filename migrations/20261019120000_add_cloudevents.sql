@@ -0,0 +1,7 @@
+alter table data.entity add column cloudevent_type text not null default '';
+alter table data.entity add column cloudevent_source text not null default '';
+alter table data.entity add column envelope_id numeric not null default 0;
+
+alter table data.entity drop constraint entity_pkey;
+alter table data.entity add constraint entity_pkey
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id);
@@ -0,0 +1,19 @@
+alter table data.entity add column normalized_id numeric not null default 0;
+alter table data.entity add column normalized_keys boolean not null default false;
+
+-- Shapes on the same exchange that only differ by key naming convention, e.g. `userId` vs `user_id`.
+-- Shapes stored before `normalized_id` was introduced are included once they're seen again.
+create view data.naming_variants as
+select
+	vhost,
+	exchange,
+	discriminator,
+	normalized_id,
+	count(*) as shapes,
+	sum(count) as count,
+	array_agg(id order by id) as ids,
+	jsonb_agg(key_paths order by id) as key_paths
+from data.entity
+where normalized_id <> 0
+group by vhost, exchange, discriminator, normalized_id
+having count(distinct key_digest) > 1;
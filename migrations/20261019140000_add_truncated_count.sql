@@ -0,0 +1 @@
+alter table data.entity add column truncated_count integer not null default 0;
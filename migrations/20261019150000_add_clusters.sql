@@ -0,0 +1,13 @@
+-- Clusters of similar shapes on an exchange, identified by the `key_digest` of their representative
+alter table data.entity add column cluster_id uuid;
+
+create table data.entity_cluster (
+	vhost text not null,
+	exchange text not null,
+	cluster_id uuid not null,
+	key_paths text[] not null,
+	shapes integer not null,
+	count bigint not null,
+	updated_at timestamptz not null default now(),
+	primary key (vhost, exchange, cluster_id)
+);
@@ -0,0 +1,18 @@
+-- Counts of a shape per time bucket, the start of which is `bucket`
+create table data.entity_counts (
+	id numeric not null,
+	key_digest uuid not null,
+	vhost text not null,
+	exchange text not null,
+	discriminator text not null,
+	cloudevent_type text not null,
+	cloudevent_source text not null,
+	envelope_id numeric not null,
+	bucket timestamptz not null,
+	count integer not null,
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id, bucket),
+	foreign key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id)
+		references data.entity on delete cascade
+);
+
+create index entity_counts_bucket_idx on data.entity_counts (bucket);
@@ -0,0 +1,26 @@
+-- Counts overflowed `integer` after ~2.1 billion observations
+drop view data.naming_variants;
+
+alter table data.entity alter column count type bigint;
+alter table data.entity alter column truncated_count type bigint;
+alter table data.entity_counts alter column count type bigint;
+
+create view data.naming_variants as
+select
+	vhost,
+	exchange,
+	discriminator,
+	normalized_id,
+	count(*) as shapes,
+	sum(count) as count,
+	array_agg(id order by id) as ids,
+	jsonb_agg(key_paths order by id) as key_paths
+from data.entity
+where normalized_id <> 0
+group by vhost, exchange, discriminator, normalized_id
+having count(distinct key_digest) > 1;
+
+-- Sum of counts, saturating at the maximum `bigint` instead of failing
+create function data.add_counts(a numeric, b numeric) returns bigint
+language sql immutable
+as $$ select least(a + b, 9223372036854775807)::bigint $$;
@@ -0,0 +1,2 @@
+-- Finds shapes to prune without scanning the whole table
+create index if not exists entity_last_seen_at_idx on data.entity (last_seen_at);
@@ -0,0 +1,19 @@
+-- Producers of a shape, told apart by the `app_id` and `user_id` message properties
+create table data.entity_producer (
+	id numeric not null,
+	key_digest uuid not null,
+	vhost text not null,
+	exchange text not null,
+	discriminator text not null,
+	cloudevent_type text not null,
+	cloudevent_source text not null,
+	envelope_id numeric not null,
+	app_id text not null,
+	user_id text not null,
+	count bigint not null,
+	first_seen_at timestamptz not null default now(),
+	last_seen_at timestamptz not null default now(),
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id, app_id, user_id),
+	foreign key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id)
+		references data.entity on delete cascade
+);
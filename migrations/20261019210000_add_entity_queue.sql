@@ -0,0 +1,20 @@
+-- Queues a shape was routed to, as far as bindings at the time tell
+create table data.entity_queue (
+	id numeric not null,
+	key_digest uuid not null,
+	vhost text not null,
+	exchange text not null,
+	discriminator text not null,
+	cloudevent_type text not null,
+	cloudevent_source text not null,
+	envelope_id numeric not null,
+	queue text not null,
+	count bigint not null,
+	first_seen_at timestamptz not null default now(),
+	last_seen_at timestamptz not null default now(),
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id, queue),
+	foreign key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id)
+		references data.entity on delete cascade
+);
+
+create index entity_queue_queue_idx on data.entity_queue (vhost, queue);
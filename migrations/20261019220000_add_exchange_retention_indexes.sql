@@ -0,0 +1,3 @@
+-- Finds shapes and time buckets past the retention of an exchange of its own
+create index if not exists entity_exchange_last_seen_at_idx on data.entity (exchange, last_seen_at);
+create index if not exists entity_counts_exchange_bucket_idx on data.entity_counts (exchange, bucket);
@@ -0,0 +1,71 @@
+-- Same tables as the Postgres migrations, without the `data` schema. Ids and digests are text as
+-- SQLite integers are signed, arrays are JSON and timestamps ISO 8601 in UTC.
+
+create table entity (
+	id text not null,
+	key_digest text not null,
+	vhost text not null,
+	exchange text not null,
+	discriminator text not null default '',
+	cloudevent_type text not null default '',
+	cloudevent_source text not null default '',
+	envelope_id text not null default '0',
+	created_at text not null default (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+	last_seen_at text not null default (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+	count integer not null default 1,
+	truncated_count integer not null default 0,
+	payload text,
+	raw_payload text,
+	routing_key text,
+	ignore_rules text not null default '[]',
+	key_paths text not null default '[]',
+	collision integer not null default 0,
+	normalized_id text not null default '0',
+	normalized_keys integer not null default 0,
+	cluster_id text,
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id)
+);
+
+create table entity_counts (
+	id text not null,
+	key_digest text not null,
+	vhost text not null,
+	exchange text not null,
+	discriminator text not null,
+	cloudevent_type text not null,
+	cloudevent_source text not null,
+	envelope_id text not null,
+	bucket text not null,
+	count integer not null,
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id, bucket),
+	foreign key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id)
+		references entity on delete cascade
+);
+
+create index entity_counts_bucket_idx on entity_counts (bucket);
+
+create table entity_cluster (
+	vhost text not null,
+	exchange text not null,
+	cluster_id text not null,
+	key_paths text not null,
+	shapes integer not null,
+	count integer not null,
+	updated_at text not null default (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+	primary key (vhost, exchange, cluster_id)
+);
+
+create view naming_variants as
+select
+	vhost,
+	exchange,
+	discriminator,
+	normalized_id,
+	count(*) as shapes,
+	sum(count) as count,
+	json_group_array(id) as ids,
+	json_group_array(json(key_paths)) as key_paths
+from entity
+where normalized_id <> '0'
+group by vhost, exchange, discriminator, normalized_id
+having count(distinct key_digest) > 1;
@@ -0,0 +1 @@
+create index entity_last_seen_at_idx on entity (last_seen_at);
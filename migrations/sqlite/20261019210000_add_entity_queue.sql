@@ -0,0 +1,19 @@
+create table entity_queue (
+	id text not null,
+	key_digest text not null,
+	vhost text not null,
+	exchange text not null,
+	discriminator text not null,
+	cloudevent_type text not null,
+	cloudevent_source text not null,
+	envelope_id text not null,
+	queue text not null,
+	count integer not null,
+	first_seen_at text not null default (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+	last_seen_at text not null default (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+	primary key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id, queue),
+	foreign key (id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source, envelope_id)
+		references entity on delete cascade
+);
+
+create index entity_queue_queue_idx on entity_queue (vhost, queue);
@@ -0,0 +1,2 @@
+create index entity_exchange_last_seen_at_idx on entity (exchange, last_seen_at);
+create index entity_counts_exchange_bucket_idx on entity_counts (exchange, bucket);
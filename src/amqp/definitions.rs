@@ -1,35 +1,78 @@
-use super::types::{Binding, Definitions, Exchange};
+use std::error::Error;
+
+use reqwest::{Client, StatusCode, Url};
+use tokio::time::Duration;
 use tracing::{debug, error};
 
-pub async fn get_definitions(url: &str) -> Result<Definitions, Box<dyn std::error::Error>> {
-	debug!("Requesting definitions for new bindings");
-	let body = reqwest::get(format!("{}/exchanges", url))
-		.await?
-		.text()
-		.await?;
-
-	let exchanges = match serde_json::from_str::<Vec<Exchange>>(&body) {
-		Ok(res) => Ok(res),
-		Err(error) => {
-			error!(?body, "Failed to parse JSON");
-			Err(Box::new(error))
-		}
-	}?;
-
-	let body = reqwest::get(format!("{}/bindings", url))
-		.await?
-		.text()
-		.await?;
-	let bindings = match serde_json::from_str::<Vec<Binding>>(&body) {
-		Ok(res) => Ok(res),
-		Err(error) => {
-			error!(?body, "Failed to parse JSON");
-			Err(Box::new(error))
+use crate::config::amqp as config;
+
+use super::auth::Auth;
+use super::tls::Tls;
+use super::types::{Binding, Definitions, Exchange};
+
+/// Client of the RabbitMQ management API.
+pub struct ManagementApi {
+	client: Client,
+	url: Url,
+	auth: Auth,
+}
+
+impl ManagementApi {
+	pub fn new(url: &str, tls: &Tls) -> ManagementApi {
+		let mut url = Url::parse(url).expect("invalid ROBSERVER_AMQP_API_ADDR");
+		let auth = Auth::from_config(&mut url);
+		let client = tls
+			.http_client(&mut url)
+			.timeout(Duration::from_millis(config::get_api_timeout()))
+			.connect_timeout(Duration::from_millis(config::get_api_connect_timeout()))
+			.build()
+			.expect("Failed to build HTTP client");
+
+		ManagementApi { client, url, auth }
+	}
+
+	async fn get(&self, path: &str) -> Result<String, Box<dyn Error>> {
+		let request = self.client.get(format!(
+			"{}/{path}",
+			self.url.as_str().trim_end_matches('/')
+		));
+		let response = self
+			.auth
+			.authorize(&self.client, request)
+			.await?
+			.send()
+			.await?;
+		if response.status() == StatusCode::UNAUTHORIZED {
+			self.auth.expire().await;
 		}
-	}?;
 
-	Ok(Definitions {
-		exchanges,
-		bindings,
-	})
+		Ok(response.error_for_status()?.text().await?)
+	}
+
+	pub async fn get_definitions(&self) -> Result<Definitions, Box<dyn Error>> {
+		debug!("Requesting definitions for new bindings");
+		let body = self.get("exchanges").await?;
+
+		let exchanges = match serde_json::from_str::<Vec<Exchange>>(&body) {
+			Ok(res) => Ok(res),
+			Err(error) => {
+				error!(?body, "Failed to parse JSON");
+				Err(Box::new(error))
+			}
+		}?;
+
+		let body = self.get("bindings").await?;
+		let bindings = match serde_json::from_str::<Vec<Binding>>(&body) {
+			Ok(res) => Ok(res),
+			Err(error) => {
+				error!(?body, "Failed to parse JSON");
+				Err(Box::new(error))
+			}
+		}?;
+
+		Ok(Definitions {
+			exchanges,
+			bindings,
+		})
+	}
 }
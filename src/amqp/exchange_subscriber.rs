@@ -1,13 +1,15 @@
 use std::collections::HashSet;
 
 use lapin::{options::*, types::FieldTable, Channel, Connection};
+use tokio::sync::watch;
 use tokio::time;
 use tokio::time::Duration;
 use tracing::{debug, error, info};
 
 use crate::config::amqp as config;
 
-use super::definitions::get_definitions;
+use super::definitions::ManagementApi;
+use super::routing::Routing;
 use super::types::*;
 use super::VHOST;
 
@@ -16,42 +18,83 @@ const ROUTING_KEY_WILDCARD: &str = "#";
 const INTERNAL_PREFIX: &str = "amq.";
 
 #[derive(PartialEq, Eq, Hash, Debug)]
-struct BindableEx {
+pub struct BindableEx {
 	pub name: String,
 	pub routing_key: String,
 }
 
-struct Binder {
-	connection: Connection,
-	channel: Channel,
+pub struct Binder {
+	channel: Option<Channel>,
 	queue: String,
+	/// Bindings to restore after reconnecting.
 	bound: HashSet<BindableEx>,
 }
 
 impl Binder {
-	pub async fn new(connection: Connection, queue: String) -> Self {
-		let channel = connection.create_channel().await.expect("create_channel");
-
+	pub fn new(queue: String) -> Self {
 		Binder {
-			connection,
-			channel,
+			channel: None,
 			queue,
 			bound: HashSet::new(),
 		}
 	}
 
-	pub async fn bind(&mut self, bindable: BindableEx) {
+	async fn channel(&mut self, connection: &Connection) -> Result<Channel, lapin::Error> {
+		match &self.channel {
+			Some(channel) if channel.status().connected() => Ok(channel.clone()),
+			_ => {
+				let channel = connection.create_channel().await?;
+				self.channel = Some(channel.clone());
+				Ok(channel)
+			}
+		}
+	}
+
+	/// Binds the queue, redeclared on a new connection, to everything it was bound to before.
+	pub async fn restore(&mut self, connection: &Connection) -> Result<(), lapin::Error> {
+		self.channel = None;
+		let mut pending = std::mem::take(&mut self.bound).into_iter();
+		let count = pending.len();
+		while let Some(bindable) = pending.next() {
+			if let Err(error) = self.bind(connection, bindable).await {
+				self.bound.extend(pending);
+				return Err(error);
+			}
+		}
+		if count > 0 {
+			info!(
+				count,
+				restored_count = self.bound.len(),
+				"Restored bindings"
+			);
+		}
+
+		Ok(())
+	}
+
+	/// Fails only if the connection is lost, keeping the binding to restore after reconnecting.
+	pub async fn bind(
+		&mut self,
+		connection: &Connection,
+		bindable: BindableEx,
+	) -> Result<(), lapin::Error> {
 		if self.bound.contains(&bindable) {
 			debug!(
 				?bindable,
 				total_bound_count = self.bound.len(),
 				"Already bound"
 			);
-			return;
+			return Ok(());
 		}
 
-		match self
-			.channel
+		let channel = match self.channel(connection).await {
+			Ok(channel) => channel,
+			Err(error) => {
+				self.bound.insert(bindable);
+				return Err(error);
+			}
+		};
+		match channel
 			.queue_bind(
 				&self.queue,
 				&bindable.name,
@@ -65,38 +108,54 @@ impl Binder {
 				info!(?bindable, "Successfully bound");
 				self.bound.insert(bindable);
 			}
-			Err(_) => {
-				self.channel = self.connection.create_channel().await.unwrap();
+			Err(error) if connection.status().connected() => {
+				// E.g. the exchange is gone, which closes the channel
+				error!(?bindable, ?error, "Failed to bind");
+				self.channel = None;
+			}
+			Err(error) => {
+				self.bound.insert(bindable);
+				return Err(error);
 			}
 		};
+
+		Ok(())
 	}
 }
 
-pub async fn exchange_subscriber(conn: Connection) {
-	let exchanges = config::get_exchanges();
-	let work_queue = config::get_queue();
-	let mut binder = Binder::new(conn, work_queue).await;
+/// Binds the work queue to exchanges as they appear, publishing their routing on `routing`.
+/// Returns once the connection is lost.
+pub async fn exchange_subscriber(
+	conn: &Connection,
+	binder: &mut Binder,
+	api: &ManagementApi,
+	routing: &watch::Sender<Routing>,
+) -> Result<(), lapin::Error> {
+	binder.restore(conn).await?;
 
+	let exchanges = config::get_exchanges();
 	if exchanges.is_empty() {
 		info!("No exchanges to bind to");
 	} else {
 		for ex in exchanges {
 			binder
-				.bind(BindableEx {
-					name: ex,
-					routing_key: ROUTING_KEY_WILDCARD.to_string(),
-				})
-				.await;
+				.bind(
+					conn,
+					BindableEx {
+						name: ex,
+						routing_key: ROUTING_KEY_WILDCARD.to_string(),
+					},
+				)
+				.await?;
 		}
 	}
 
-	let api_url = config::get_api_url();
 	let queue_name = config::get_queue();
 	let mut interval = time::interval(Duration::from_millis(5000));
 
 	loop {
 		interval.tick().await;
-		match get_definitions(&api_url).await {
+		match api.get_definitions().await {
 			Ok(mut result) => {
 				result.bindings.retain(|binding| {
 					binding.vhost == VHOST
@@ -117,11 +176,14 @@ pub async fn exchange_subscriber(conn: Connection) {
 								let binding = &result.bindings[binding_index];
 								if binding.source == ex.name {
 									binder
-										.bind(BindableEx {
-											name: ex.name.clone(),
-											routing_key: binding.routing_key.clone(),
-										})
-										.await;
+										.bind(
+											conn,
+											BindableEx {
+												name: ex.name.clone(),
+												routing_key: binding.routing_key.clone(),
+											},
+										)
+										.await?;
 									found_count += 1;
 								}
 							}
@@ -133,14 +195,19 @@ pub async fn exchange_subscriber(conn: Connection) {
 						}
 						_ => {
 							binder
-								.bind(BindableEx {
-									name: ex.name.clone(),
-									routing_key: ROUTING_KEY_WILDCARD.to_string(),
-								})
-								.await;
+								.bind(
+									conn,
+									BindableEx {
+										name: ex.name.clone(),
+										routing_key: ROUTING_KEY_WILDCARD.to_string(),
+									},
+								)
+								.await?;
 						}
 					};
 				}
+
+				routing.send_replace(Routing::new(result, VHOST));
 			}
 			Err(error) => {
 				error!(error, "Failed to get definitions");
@@ -1,18 +1,27 @@
+mod auth;
 mod definitions;
 mod exchange_subscriber;
 mod payload_parser;
+mod routing;
+mod tls;
 mod types;
 
+use std::io;
+
 use lapin::{options::*, types::FieldTable, Channel, Connection, ConnectionProperties, Queue};
-use tokio::sync::mpsc;
-use tokio::time::{timeout, Duration};
-use tracing::info;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{error, info, warn};
 
 use crate::config::amqp as config;
+use crate::db::Backoff;
 use crate::payload::Payload;
 
-use exchange_subscriber::exchange_subscriber;
-use payload_parser::payload_parser;
+use definitions::ManagementApi;
+use exchange_subscriber::{exchange_subscriber, Binder};
+use payload_parser::{payload_parser, DeliveryTags};
+use routing::Routing;
+use tls::Tls;
 
 // Vhost is currently hard-coded to "/"
 const VHOST: &str = "/";
@@ -32,34 +41,88 @@ async fn declare_work_queue(channel: &Channel, queue_name: &str) -> Result<Queue
 	channel.queue_declare(queue_name, options, fields).await
 }
 
-pub async fn listen_messages(payloads: mpsc::Sender<Payload>) {
+async fn connect(
+	tls: &Tls,
+	addr: &str,
+	work_queue: &str,
+) -> Result<(Connection, Channel), lapin::Error> {
 	info!("Connecting...");
-	let addr = config::get_url();
-	let work_queue = config::get_queue();
-
-	let conn = timeout(Duration::from_secs(5), async {
-		Connection::connect(&addr, ConnectionProperties::default())
-			.await
-			.expect("Failed to connect to RabbitMQ")
-	})
+	let conn = timeout(
+		Duration::from_secs(5),
+		tls.connect(addr, ConnectionProperties::default()),
+	)
 	.await
-	.expect("Failed to connect to RabbitMQ");
-
+	.map_err(|_| lapin::Error::IOError(io::Error::from(io::ErrorKind::TimedOut).into()))??;
+	conn.on_error(|error| {
+		error!(?error, "Connection error");
+	});
 	info!("Connected");
 
-	let mut channel = conn.create_channel().await.expect("create_channel");
-	let queue = declare_work_queue(&channel, &work_queue).await;
+	let mut channel = conn.create_channel().await?;
+	let queue = declare_work_queue(&channel, work_queue).await;
 	match queue {
 		Ok(_) => info!(?queue, "Declared queue"),
 		Err(lapin::Error::ProtocolError(ref e)) if e.get_id() == 406 /* PRECONDITION FAILED */ => {
 			info!("Queue already declared");
-			channel = conn.create_channel().await.expect("create_channel");
+			channel = conn.create_channel().await?;
 		},
-		Err(err) => panic!("Unrecoverable error declaring queue: {:?}", err),
+		Err(err @ lapin::Error::ProtocolError(_)) => panic!("Unrecoverable error declaring queue: {:?}", err),
+		Err(err) => return Err(err),
 	};
 
-	let parser = payload_parser(payloads, channel);
-	let subscriber = exchange_subscriber(conn);
+	Ok((conn, channel))
+}
+
+/// Consumes messages, sending their payloads for processing. Delivery tags received on `acks` are
+/// acknowledged along with all the deliveries preceding them.
+///
+/// Whenever the connection is lost, reconnects with a backoff, redeclaring the work queue and
+/// restoring its bindings.
+pub async fn listen_messages(
+	payloads: mpsc::Sender<Payload>,
+	mut acks: mpsc::UnboundedReceiver<u64>,
+) {
+	let addr = config::get_url();
+	let work_queue = config::get_queue();
+	let tls = Tls::from_config();
+	let api = ManagementApi::new(&config::get_api_url(), &tls);
+	let mut backoff = Backoff::new(
+		Duration::from_millis(config::get_reconnect_min_delay()),
+		Duration::from_millis(config::get_reconnect_max_delay()),
+	);
+
+	let (routing_tx, routing_rx) = watch::channel(Routing::default());
+	let mut binder = Binder::new(work_queue.clone());
+	let mut tags = DeliveryTags::default();
+
+	loop {
+		let result = match connect(&tls, &addr, &work_queue).await {
+			Ok((conn, channel)) => {
+				backoff.reset();
+				let parser = payload_parser(&payloads, &mut acks, channel, &routing_rx, &mut tags);
+				let subscriber = exchange_subscriber(&conn, &mut binder, &api, &routing_tx);
+				let result = tokio::select!(
+					result = parser => result,
+					result = subscriber => result,
+				);
+				if conn.status().connected() {
+					let _ = conn.close(0, "Reconnecting").await;
+				}
+				result
+			}
+			Err(error) => Err(error),
+		};
 
-	let _result = tokio::join!(parser, subscriber);
+		let delay = backoff.next_delay();
+		match result {
+			Ok(()) => warn!(?delay, "Consumer cancelled, reconnecting to RabbitMQ"),
+			Err(error) => error!(
+				%error,
+				attempts = backoff.attempts(),
+				?delay,
+				"RabbitMQ connection failed, reconnecting"
+			),
+		}
+		sleep(delay).await;
+	}
 }
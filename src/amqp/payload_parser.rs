@@ -1,28 +1,108 @@
 use futures_lite::StreamExt;
-use lapin::{options::*, types::FieldTable, Channel};
-use tokio::sync::mpsc;
+use lapin::message::Delivery;
+use lapin::{options::*, types::AMQPValue, types::FieldTable, Channel};
+use tokio::sync::{mpsc, watch};
 use tracing::{debug, error, info};
 
 use crate::config::amqp as config;
-use crate::payload::Payload;
+use crate::config::shape as shape_config;
+use crate::payload::{Payload, Producer, Properties};
 
+use super::routing::Routing;
 use super::CONSUMER_TAG;
 use super::VHOST;
 
-pub async fn payload_parser(payloads: mpsc::Sender<Payload>, channel: Channel) {
+fn properties(delivery: &Delivery) -> Properties {
+	let headers = delivery
+		.properties
+		.headers()
+		.iter()
+		.flat_map(|headers| headers.inner())
+		.filter_map(|(name, value)| match value {
+			AMQPValue::LongString(value) => Some((name.to_string(), value.to_string())),
+			AMQPValue::ShortString(value) => Some((name.to_string(), value.to_string())),
+			_ => None,
+		})
+		.collect();
+
+	let content_type = delivery
+		.properties
+		.content_type()
+		.as_ref()
+		.and_then(|content_type| content_type.as_str().split(';').next())
+		.map(|content_type| content_type.trim().to_lowercase());
+
+	let producer = Producer {
+		app_id: delivery
+			.properties
+			.app_id()
+			.as_ref()
+			.map(ToString::to_string)
+			.unwrap_or_default(),
+		user_id: delivery
+			.properties
+			.user_id()
+			.as_ref()
+			.map(ToString::to_string)
+			.unwrap_or_default(),
+	};
+
+	Properties {
+		content_type,
+		headers,
+		producer,
+	}
+}
+
+/// Delivery tags made unique across channels, as they start over on every channel. Acks of
+/// deliveries from a closed channel are told apart and dropped: the broker redelivers those.
+#[derive(Default)]
+pub struct DeliveryTags {
+	/// Tags issued on previous channels.
+	offset: u64,
+	last: u64,
+}
+
+impl DeliveryTags {
+	/// Continues on a new channel.
+	fn next_channel(&mut self) {
+		self.offset = self.last;
+	}
+
+	fn issue(&mut self, delivery_tag: u64) -> u64 {
+		self.last = self.offset + delivery_tag;
+		self.last
+	}
+
+	/// The tag on the current channel, if issued on it.
+	fn on_channel(&self, tag: u64) -> Option<u64> {
+		tag.checked_sub(self.offset).filter(|tag| *tag > 0)
+	}
+}
+
+/// Consumes from the work queue until the channel or the connection is closed. Delivery tags
+/// received on `acks` are acknowledged along with all the deliveries preceding them.
+pub async fn payload_parser(
+	payloads: &mpsc::Sender<Payload>,
+	acks: &mut mpsc::UnboundedReceiver<u64>,
+	channel: Channel,
+	routing: &watch::Receiver<Routing>,
+	tags: &mut DeliveryTags,
+) -> Result<(), lapin::Error> {
 	let prefetch = config::get_prefetch();
+	let ack_after_commit = config::get_ack_after_commit();
 	let work_queue = config::get_queue();
+	let options = shape_config::get_options();
 
 	channel
 		.basic_qos(prefetch, BasicQosOptions::default())
-		.await
-		.expect("Failed to set prefetch");
+		.await?;
 
 	channel.on_error(|error| {
 		error!(?error, "Channel error");
 	});
 
-	info!("Consuming");
+	info!(ack_after_commit, "Consuming");
 	let mut consumer = channel
 		.basic_consume(
 			&work_queue,
@@ -30,31 +110,101 @@ pub async fn payload_parser(payloads: mpsc::Sender<Payload>, channel: Channel) {
 			BasicConsumeOptions::default(),
 			FieldTable::default(),
 		)
-		.await
-		.expect("Failed to consume");
+		.await?;
+	tags.next_channel();
 
-	while let Some(delivery) = consumer.next().await {
-		let message = delivery.unwrap();
+	loop {
+		let delivery = tokio::select! {
+			delivery = consumer.next() => delivery,
+			Some(tag) = acks.recv(), if ack_after_commit => {
+				match tags.on_channel(tag) {
+					Some(delivery_tag) => {
+						debug!(delivery_tag, "Acking committed deliveries");
+						channel
+							.basic_ack(delivery_tag, BasicAckOptions { multiple: true })
+							.await?;
+					}
+					None => debug!(tag, "Dropping ack of a closed channel"),
+				}
+				continue;
+			}
+		};
+		let Some(delivery) = delivery else {
+			break;
+		};
+		let message = delivery?;
 		debug!(?message, "Message recieved");
 
-		let payload = Payload::new(
+		let properties = properties(&message);
+		let exchange = message.exchange.to_string();
+		let routing_key = message.routing_key.to_string();
+		let queues = routing
+			.borrow()
+			.queues(&exchange, &routing_key, &properties.headers);
+		let mut decoded = Payload::decode(
 			message.data,
 			VHOST.to_string(),
-			message.exchange.to_string(),
-			message.routing_key.to_string(),
+			exchange,
+			routing_key,
+			&properties,
+			&options,
 		);
+		for payload in &mut decoded {
+			payload.queues.clone_from(&queues);
+		}
+
+		// At least once: the last payload carries the delivery to ack once its count is stored
+		let ack_now = match decoded.last_mut() {
+			Some(payload) if ack_after_commit => {
+				payload.delivery_tag = Some(tags.issue(message.delivery_tag));
+				false
+			}
+			_ => true,
+		};
 
-		payloads
-			.send(payload)
-			.await
-			.expect("Could not send payload for processing");
+		for payload in decoded {
+			payloads
+				.send(payload)
+				.await
+				.expect("Could not send payload for processing");
+		}
 
-		message
-			.acker
-			.ack(BasicAckOptions { multiple: true })
-			.await
-			.expect("Failed to ack");
+		if ack_now {
+			message
+				.acker
+				.ack(BasicAckOptions {
+					// Earlier deliveries may not be committed yet
+					multiple: !ack_after_commit,
+				})
+				.await?;
+		}
 	}
 
 	info!("Payload parser finished");
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn delivery_tags() {
+		let mut tags = DeliveryTags::default();
+		tags.next_channel();
+		assert_eq!(tags.issue(1), 1);
+		assert_eq!(tags.issue(2), 2);
+		assert_eq!(tags.on_channel(2), Some(2));
+
+		tags.next_channel();
+		assert_eq!(tags.on_channel(2), None);
+		assert_eq!(tags.issue(1), 3);
+		assert_eq!(tags.on_channel(3), Some(1));
+
+		// Nothing delivered on a channel
+		tags.next_channel();
+		tags.next_channel();
+		assert_eq!(tags.issue(1), 4);
+		assert_eq!(tags.on_channel(4), Some(1));
+	}
 }
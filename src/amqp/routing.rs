@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde_json::Value;
+
+use super::types::{Binding, Definitions, ExchangeType};
+
+const EXCHANGE: &str = "exchange";
+const QUEUE: &str = "queue";
+
+/// Exchanges and bindings of a vhost, telling which queues a message published to an exchange is
+/// routed to.
+#[derive(Default)]
+pub struct Routing {
+	exchanges: HashMap<String, ExchangeType>,
+	/// Bindings by source exchange.
+	bindings: HashMap<String, Vec<Binding>>,
+}
+
+impl Routing {
+	pub fn new(definitions: Definitions, vhost: &str) -> Routing {
+		let exchanges = definitions
+			.exchanges
+			.into_iter()
+			.filter(|exchange| exchange.vhost == vhost)
+			.map(|exchange| (exchange.name, exchange.r#type))
+			.collect();
+		let mut bindings: HashMap<String, Vec<Binding>> = HashMap::new();
+		for binding in definitions.bindings {
+			if binding.vhost == vhost {
+				bindings
+					.entry(binding.source.clone())
+					.or_default()
+					.push(binding);
+			}
+		}
+
+		Routing {
+			exchanges,
+			bindings,
+		}
+	}
+
+	/// Queues a message is routed to, following exchange-to-exchange bindings.
+	pub fn queues(
+		&self,
+		exchange: &str,
+		routing_key: &str,
+		headers: &BTreeMap<String, String>,
+	) -> Vec<String> {
+		let mut queues = BTreeSet::new();
+		let mut visited = HashSet::new();
+		self.route(exchange, routing_key, headers, &mut queues, &mut visited);
+		queues.into_iter().collect()
+	}
+
+	fn route<'a>(
+		&'a self,
+		exchange: &'a str,
+		routing_key: &str,
+		headers: &BTreeMap<String, String>,
+		queues: &mut BTreeSet<String>,
+		visited: &mut HashSet<&'a str>,
+	) {
+		if !visited.insert(exchange) {
+			return;
+		}
+		let (Some(r#type), Some(bindings)) =
+			(self.exchanges.get(exchange), self.bindings.get(exchange))
+		else {
+			return;
+		};
+
+		for binding in bindings {
+			let routed = match r#type {
+				ExchangeType::Direct => binding.routing_key == routing_key,
+				ExchangeType::Fanout => true,
+				ExchangeType::Topic => topic_matches(&binding.routing_key, routing_key),
+				ExchangeType::Headers => headers_match(&binding.arguments, headers),
+			};
+			if !routed {
+				continue;
+			}
+			match binding.destination_type.as_str() {
+				QUEUE => {
+					queues.insert(binding.destination.clone());
+				}
+				EXCHANGE => self.route(&binding.destination, routing_key, headers, queues, visited),
+				_ => {}
+			}
+		}
+	}
+}
+
+/// Words of a topic routing or binding key. An empty key has none.
+fn words(key: &str) -> Vec<&str> {
+	if key.is_empty() {
+		Vec::new()
+	} else {
+		key.split('.').collect()
+	}
+}
+
+/// Whether a topic binding key matches a routing key: `*` stands for exactly one word, `#` for
+/// zero or more.
+fn topic_matches(binding_key: &str, routing_key: &str) -> bool {
+	let key = words(routing_key);
+	// Whether the binding key words so far match the first `j` words of the routing key
+	let mut matched = vec![false; key.len() + 1];
+	matched[0] = true;
+	for word in words(binding_key) {
+		if word == "#" {
+			for j in 1..=key.len() {
+				matched[j] = matched[j] || matched[j - 1];
+			}
+		} else {
+			for j in (1..=key.len()).rev() {
+				matched[j] = matched[j - 1] && (word == "*" || word == key[j - 1]);
+			}
+			matched[0] = false;
+		}
+	}
+
+	matched[key.len()]
+}
+
+/// Whether message headers match the arguments of a headers exchange binding, all of them or any
+/// depending on `x-match`. Only string headers are known, compared to the arguments as strings.
+fn headers_match(
+	arguments: &serde_json::Map<String, Value>,
+	headers: &BTreeMap<String, String>,
+) -> bool {
+	let x_match = arguments
+		.get("x-match")
+		.and_then(Value::as_str)
+		.unwrap_or("all");
+	let with_x = x_match.ends_with("-with-x");
+	let mut matches = arguments
+		.iter()
+		.filter(|(name, _)| with_x || !name.starts_with("x-"))
+		.map(|(name, value)| match (value, headers.get(name)) {
+			(_, None) => false,
+			(Value::Null, Some(_)) => true,
+			(Value::String(value), Some(header)) => value == header,
+			(value, Some(header)) => {
+				serde_json::from_str::<Value>(header).is_ok_and(|header| header == *value)
+			}
+		});
+
+	if x_match.starts_with("any") {
+		matches.any(|matched| matched)
+	} else {
+		matches.all(|matched| matched)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+	use crate::amqp::types::Exchange;
+
+	fn binding(source: &str, destination: &str, routing_key: &str, arguments: Value) -> Binding {
+		Binding {
+			source: source.to_string(),
+			vhost: String::from("/"),
+			destination: destination.to_string(),
+			destination_type: String::from(if destination.starts_with("ex.") {
+				EXCHANGE
+			} else {
+				QUEUE
+			}),
+			routing_key: routing_key.to_string(),
+			arguments: arguments.as_object().cloned().unwrap_or_default(),
+		}
+	}
+
+	fn exchange(name: &str, r#type: ExchangeType) -> Exchange {
+		Exchange {
+			name: name.to_string(),
+			vhost: String::from("/"),
+			r#type,
+			auto_delete: false,
+			internal: false,
+		}
+	}
+
+	fn headers(headers: &[(&str, &str)]) -> BTreeMap<String, String> {
+		headers
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn topics() {
+		assert!(topic_matches("a.b", "a.b"));
+		assert!(!topic_matches("a.b", "a.b.c"));
+		assert!(topic_matches("a.*", "a.b"));
+		assert!(!topic_matches("a.*", "a"));
+		assert!(!topic_matches("a.*", "a.b.c"));
+		assert!(topic_matches("a.#", "a"));
+		assert!(topic_matches("a.#", "a.b.c"));
+		assert!(topic_matches("#.c", "a.b.c"));
+		assert!(topic_matches("a.#.c", "a.c"));
+		assert!(topic_matches("*.#.c", "a.b.b.c"));
+		assert!(!topic_matches("*.#.c", "c"));
+		assert!(topic_matches("#", ""));
+		assert!(topic_matches("", ""));
+		assert!(!topic_matches("*", ""));
+	}
+
+	#[test]
+	fn topics_many_wildcards() {
+		let binding_key = vec!["#"; 30].join(".");
+		let routing_key = vec!["a"; 200].join(".");
+		assert!(!topic_matches(&format!("{binding_key}.b"), &routing_key));
+		assert!(topic_matches(&format!("{binding_key}.a.#.*"), &routing_key));
+		assert!(!topic_matches(
+			&format!("#.b.{binding_key}.a"),
+			&routing_key
+		));
+		assert!(topic_matches(
+			&format!("#.b.{binding_key}.a"),
+			&format!("{routing_key}.b.a")
+		));
+	}
+
+	#[test]
+	fn header_matching() {
+		let all = json!({"x-match": "all", "type": "report", "format": "pdf"});
+		let all = all.as_object().unwrap();
+		assert!(headers_match(
+			all,
+			&headers(&[("type", "report"), ("format", "pdf"), ("other", "1")])
+		));
+		assert!(!headers_match(all, &headers(&[("type", "report")])));
+
+		let any = json!({"x-match": "any", "type": "report", "format": "pdf"});
+		let any = any.as_object().unwrap();
+		assert!(headers_match(any, &headers(&[("format", "pdf")])));
+		assert!(!headers_match(any, &headers(&[("format", "csv")])));
+		assert!(!headers_match(any, &headers(&[])));
+
+		// All by default, non-string arguments compared as strings
+		let numeric = json!({"version": 2});
+		let numeric = numeric.as_object().unwrap();
+		assert!(headers_match(numeric, &headers(&[("version", "2")])));
+		assert!(headers_match(&serde_json::Map::new(), &headers(&[])));
+	}
+
+	#[test]
+	fn routes() {
+		let routing = Routing::new(
+			Definitions {
+				exchanges: vec![
+					exchange("orders", ExchangeType::Topic),
+					exchange("ex.audit", ExchangeType::Fanout),
+					exchange("ex.loop", ExchangeType::Fanout),
+					exchange("reports", ExchangeType::Headers),
+					exchange("direct", ExchangeType::Direct),
+				],
+				bindings: vec![
+					binding("orders", "billing", "order.*.created", json!({})),
+					binding("orders", "shipping", "order.#", json!({})),
+					binding("orders", "ex.audit", "#", json!({})),
+					binding("ex.audit", "audit", "", json!({})),
+					binding("ex.audit", "ex.loop", "", json!({})),
+					binding("ex.loop", "ex.audit", "", json!({})),
+					binding("reports", "pdf", "", json!({"format": "pdf"})),
+					binding("direct", "exact", "a.b", json!({})),
+				],
+			},
+			"/",
+		);
+
+		let none = headers(&[]);
+		assert_eq!(
+			routing.queues("orders", "order.eu.created", &none),
+			vec!["audit", "billing", "shipping"]
+		);
+		assert_eq!(
+			routing.queues("orders", "order.eu.created.late", &none),
+			vec!["audit", "shipping"]
+		);
+		assert_eq!(routing.queues("ex.audit", "anything", &none), vec!["audit"]);
+		assert_eq!(
+			routing.queues("reports", "", &headers(&[("format", "pdf")])),
+			vec!["pdf"]
+		);
+		assert!(routing.queues("reports", "", &none).is_empty());
+		assert_eq!(routing.queues("direct", "a.b", &none), vec!["exact"]);
+		assert!(routing.queues("direct", "a.*", &none).is_empty());
+		assert!(routing.queues("unknown", "a.b", &none).is_empty());
+	}
+}
@@ -8,7 +8,9 @@ pub struct Binding {
 	pub destination: String,
 	pub destination_type: String,
 	pub routing_key: String,
-	// arguments: ??,
+	/// Matched against message headers by headers exchanges.
+	#[serde(default)]
+	pub arguments: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug)]
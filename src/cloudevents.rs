@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::hash::{shape_of, Traversal};
+
+const SPEC_VERSIONS: [&str; 2] = ["1.0", "0.3"];
+const DATA: &str = "data";
+const DATA_BASE64: &str = "data_base64";
+const SPECVERSION: &str = "specversion";
+/// Prefixes of AMQP headers carrying attributes in binary mode.
+const HEADER_PREFIXES: [&str; 3] = ["ce_", "cloudEvents:", "cloudEvents_"];
+
+/// Context attributes of a CloudEvent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Envelope {
+	pub r#type: String,
+	pub source: String,
+	/// Fingerprint of the attribute names, like the shape of the data, but for the envelope.
+	pub id: u64,
+}
+
+impl Envelope {
+	fn from_attributes(attributes: &Map<String, Value>) -> Result<Envelope, String> {
+		let attribute = |name: &str| match attributes.get(name) {
+			Some(Value::String(value)) if !value.is_empty() => Ok(value.clone()),
+			Some(_) => Err(format!("invalid attribute {name:?}")),
+			None => Err(format!("missing attribute {name:?}")),
+		};
+
+		let specversion = attribute(SPECVERSION)?;
+		if !SPEC_VERSIONS.contains(&specversion.as_str()) {
+			return Err(format!("unsupported specversion {specversion:?}"));
+		}
+		attribute("id")?;
+
+		Ok(Envelope {
+			r#type: attribute("type")?,
+			source: attribute("source")?,
+			id: shape_of(&Value::Object(attributes.clone()), Traversal::default()).id,
+		})
+	}
+}
+
+/// Envelope of a structured mode event and its `data` member, if it's JSON.
+pub fn from_structured(event: &Value) -> Result<(Envelope, Option<&Value>), String> {
+	let Value::Object(event) = event else {
+		return Err(String::from("event is not an object"));
+	};
+	let mut attributes = event.clone();
+	attributes.remove(DATA);
+	attributes.remove(DATA_BASE64);
+
+	Ok((Envelope::from_attributes(&attributes)?, event.get(DATA)))
+}
+
+/// Envelope of a binary mode event or `None` if there are no CloudEvents headers.
+pub fn from_headers(headers: &BTreeMap<String, String>) -> Option<Result<Envelope, String>> {
+	let attributes: Map<String, Value> = headers
+		.iter()
+		.filter_map(|(name, value)| {
+			HEADER_PREFIXES
+				.iter()
+				.find_map(|prefix| name.strip_prefix(prefix))
+				.map(|name| (name.to_string(), Value::from(value.as_str())))
+		})
+		.collect();
+
+	if attributes.is_empty() {
+		None
+	} else {
+		Some(Envelope::from_attributes(&attributes))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const EVENT: &str = r#"
+		{
+			"specversion": "1.0",
+			"type": "com.example.order.created",
+			"source": "/orders",
+			"id": "A234-1234-1234",
+			"time": "2018-04-05T17:31:00Z",
+			"datacontenttype": "application/json",
+			"data": { "orderId": 1, "items": [] }
+		}"#;
+
+	fn headers(headers: &[(&str, &str)]) -> BTreeMap<String, String> {
+		headers
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn structured() {
+		let event: Value = serde_json::from_str(EVENT).unwrap();
+		let (envelope, data) = from_structured(&event).unwrap();
+
+		assert_eq!(envelope.r#type, "com.example.order.created");
+		assert_eq!(envelope.source, "/orders");
+		assert_eq!(data, Some(&event["data"]));
+	}
+
+	#[test]
+	fn structured_invalid() {
+		let mut event: Value = serde_json::from_str(EVENT).unwrap();
+		event["specversion"] = Value::from("2.0");
+		assert!(from_structured(&event).is_err());
+
+		event["specversion"] = Value::from("1.0");
+		event.as_object_mut().unwrap().remove("source");
+		assert!(from_structured(&event).is_err());
+
+		assert!(from_structured(&Value::from("event")).is_err());
+	}
+
+	#[test]
+	fn structured_without_data() {
+		let mut event: Value = serde_json::from_str(EVENT).unwrap();
+		event.as_object_mut().unwrap().remove("data");
+		let (_, data) = from_structured(&event).unwrap();
+
+		assert_eq!(data, None);
+	}
+
+	#[test]
+	fn binary() {
+		let envelope = from_headers(&headers(&[
+			("ce_specversion", "1.0"),
+			("ce_type", "com.example.order.created"),
+			("ce_source", "/orders"),
+			("ce_id", "A234-1234-1234"),
+			("ce_time", "2018-04-05T17:31:00Z"),
+			("ce_datacontenttype", "application/json"),
+			("x-other", "value"),
+		]))
+		.unwrap()
+		.unwrap();
+
+		assert_eq!(envelope.r#type, "com.example.order.created");
+		assert_eq!(envelope.source, "/orders");
+
+		let event: Value = serde_json::from_str(EVENT).unwrap();
+		let (structured, _) = from_structured(&event).unwrap();
+		assert_eq!(envelope, structured);
+	}
+
+	#[test]
+	fn binary_invalid() {
+		assert_eq!(from_headers(&headers(&[("x-other", "value")])), None);
+		assert!(from_headers(&headers(&[("ce_specversion", "1.0")]))
+			.unwrap()
+			.is_err());
+	}
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeSet;
+
+/// Jaccard similarity of two sets of key paths: the size of the intersection divided by the
+/// size of the union. Two empty sets are considered equal.
+pub fn jaccard(a: &BTreeSet<&str>, b: &BTreeSet<&str>) -> f64 {
+	let union = a.union(b).count();
+	if union == 0 {
+		return 1.0;
+	}
+	a.intersection(b).count() as f64 / union as f64
+}
+
+/// Groups shapes, given as lists of key paths, into clusters of similar shapes.
+///
+/// Shapes are expected in the order of priority, e.g. the most common first. Each shape joins the
+/// most similar cluster whose representative is at least `threshold` similar to it, or becomes
+/// the representative of a new cluster. Returns the index of the representative for every shape.
+pub fn cluster<S: AsRef<str>>(shapes: &[Vec<S>], threshold: f64) -> Vec<usize> {
+	let sets: Vec<BTreeSet<&str>> = shapes
+		.iter()
+		.map(|key_paths| key_paths.iter().map(AsRef::as_ref).collect())
+		.collect();
+	let mut representatives: Vec<usize> = Vec::new();
+	let mut assignments = Vec::with_capacity(sets.len());
+
+	for (index, set) in sets.iter().enumerate() {
+		let closest = representatives
+			.iter()
+			.map(|r| (*r, jaccard(&sets[*r], set)))
+			.filter(|(_, similarity)| *similarity >= threshold)
+			.max_by(|a, b| a.1.total_cmp(&b.1));
+		match closest {
+			Some((representative, _)) => assignments.push(representative),
+			None => {
+				representatives.push(index);
+				assignments.push(index);
+			}
+		}
+	}
+
+	assignments
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn set<'a>(key_paths: &[&'a str]) -> BTreeSet<&'a str> {
+		key_paths.iter().copied().collect()
+	}
+
+	#[test]
+	fn similarity() {
+		assert_eq!(jaccard(&set(&["a", "b"]), &set(&["b", "a"])), 1.0);
+		assert_eq!(jaccard(&set(&["a", "b"]), &set(&["c"])), 0.0);
+		assert_eq!(jaccard(&set(&["a", "b", "c"]), &set(&["a", "b", "d"])), 0.5);
+		assert_eq!(jaccard(&set(&[]), &set(&[])), 1.0);
+		assert_eq!(jaccard(&set(&["a"]), &set(&[])), 0.0);
+	}
+
+	#[test]
+	fn clusters() {
+		let shapes = vec![
+			vec!["id", "name", "address", "address.city"],
+			vec!["type", "order", "order.id", "order.items"],
+			vec!["id", "name", "address", "address.city", "address.zip"],
+			vec!["type", "order", "order.id", "order.items", "order.total"],
+			vec!["debug"],
+			vec!["id", "name", "address", "address.city", "phone"],
+		];
+
+		assert_eq!(cluster(&shapes, 0.7), vec![0, 1, 0, 1, 4, 0]);
+		assert_eq!(cluster(&shapes, 0.9), vec![0, 1, 2, 3, 4, 5]);
+		assert_eq!(cluster(&shapes, 0.0), vec![0, 0, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn clusters_closest() {
+		let shapes = vec![
+			vec!["a", "b", "c", "d"],
+			vec!["e", "f", "g", "h"],
+			vec!["a", "b", "c", "e", "f", "g", "h"],
+		];
+
+		assert_eq!(cluster(&shapes, 0.3), vec![0, 1, 1]);
+	}
+
+	#[test]
+	fn clusters_empty() {
+		let shapes: Vec<Vec<String>> = vec![];
+		assert_eq!(cluster(&shapes, 0.5), Vec::<usize>::new());
+	}
+}
@@ -0,0 +1,102 @@
+use serde_json::Value;
+
+/// Start of the binary `COPY` format: signature, flags and header extension length.
+const HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+/// Field count marking the end of the data.
+const TRAILER: i16 = -1;
+/// Version of the binary `jsonb` representation.
+const JSONB_VERSION: u8 = 1;
+
+/// Rows in PostgreSQL's binary `COPY` format, written field by field.
+///
+/// Every value is encoded as its column type expects it in binary, so the types written must match
+/// the table copied into.
+pub struct CopyEncoder {
+	buf: Vec<u8>,
+}
+
+impl CopyEncoder {
+	pub fn new() -> CopyEncoder {
+		CopyEncoder {
+			buf: HEADER.to_vec(),
+		}
+	}
+
+	/// Starts a row of `fields` fields.
+	pub fn row(&mut self, fields: i16) {
+		self.buf.extend_from_slice(&fields.to_be_bytes());
+	}
+
+	fn field(&mut self, value: &[u8]) {
+		let len = i32::try_from(value.len()).expect("field exceeds the COPY size limit");
+		self.buf.extend_from_slice(&len.to_be_bytes());
+		self.buf.extend_from_slice(value);
+	}
+
+	pub fn null(&mut self) {
+		self.buf.extend_from_slice(&(-1i32).to_be_bytes());
+	}
+
+	pub fn text(&mut self, value: &str) {
+		self.field(value.as_bytes());
+	}
+
+	pub fn bigint(&mut self, value: i64) {
+		self.field(&value.to_be_bytes());
+	}
+
+	pub fn boolean(&mut self, value: bool) {
+		self.field(&[u8::from(value)]);
+	}
+
+	pub fn jsonb(&mut self, value: &Value) {
+		let mut field = vec![JSONB_VERSION];
+		serde_json::to_writer(&mut field, value).expect("Failed to serialize JSON");
+		self.field(&field);
+	}
+
+	pub fn len(&self) -> usize {
+		self.buf.len()
+	}
+
+	/// Takes the data encoded so far, e.g. to send it in chunks.
+	pub fn take(&mut self) -> Vec<u8> {
+		std::mem::take(&mut self.buf)
+	}
+
+	/// Ends the data and returns what's left of it.
+	pub fn finish(mut self) -> Vec<u8> {
+		self.buf.extend_from_slice(&TRAILER.to_be_bytes());
+		self.buf
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encoding() {
+		let mut encoder = CopyEncoder::new();
+		encoder.row(5);
+		encoder.text("ab");
+		encoder.null();
+		encoder.bigint(-2);
+		encoder.boolean(true);
+		encoder.jsonb(&serde_json::json!({"a": 1}));
+
+		let mut expected = HEADER.to_vec();
+		expected.extend_from_slice(&[0, 5]);
+		expected.extend_from_slice(&[0, 0, 0, 2, b'a', b'b']);
+		expected.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+		expected.extend_from_slice(&[0, 0, 0, 8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+		expected.extend_from_slice(&[0, 0, 0, 1, 1]);
+		expected.extend_from_slice(&[0, 0, 0, 8, 1]);
+		expected.extend_from_slice(br#"{"a":1}"#);
+
+		assert_eq!(encoder.len(), expected.len());
+		let taken = encoder.take();
+		assert_eq!(taken, expected);
+		assert_eq!(encoder.finish(), vec![0xFF, 0xFF]);
+	}
+}
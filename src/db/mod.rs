@@ -0,0 +1,878 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::Path;
+use std::str::FromStr;
+
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, SqlitePool};
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tracing::{error, info, warn};
+
+pub use self::retry::Backoff;
+use self::spool::Spool;
+use crate::cluster;
+use crate::config;
+use crate::payload::{Payload, Producer};
+
+mod copy;
+mod postgres;
+mod retry;
+mod spool;
+mod sqlite;
+
+/// Seconds between merging old time buckets.
+const DOWNSAMPLE_INTERVAL: u64 = 60 * 60;
+
+/// Count for a `bigint` column, saturating at its maximum.
+fn to_count(count: usize) -> i64 {
+	i64::try_from(count).unwrap_or(i64::MAX)
+}
+
+/// Digest of the key paths as a UUID, telling apart distinct shapes sharing the same `id`. It's
+/// the MD5 of the paths joined by newlines, as it has always been.
+fn key_digest(key_paths: &[String]) -> String {
+	let digest: String = Md5::digest(key_paths.join("\n"))
+		.iter()
+		.map(|b| format!("{b:02x}"))
+		.collect();
+	format!(
+		"{}-{}-{}-{}-{}",
+		&digest[0..8],
+		&digest[8..12],
+		&digest[12..16],
+		&digest[16..20],
+		&digest[20..32]
+	)
+}
+
+/// Observations of a shape, in total, per producer and per queue routed to.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Counts {
+	pub count: usize,
+	/// Few producers publish the same shape, so they're looked up linearly.
+	pub producers: Vec<(Producer, usize)>,
+	/// Likewise few queues.
+	pub queues: Vec<(String, usize)>,
+}
+
+/// Counts `key` in a list of few distinct keys.
+fn count_in<K: PartialEq>(counts: &mut Vec<(K, usize)>, key: K) {
+	match counts.iter_mut().find(|(k, _)| *k == key) {
+		Some((_, count)) => *count = count.saturating_add(1),
+		None => counts.push((key, 1)),
+	}
+}
+
+impl Counts {
+	fn add(&mut self, producer: Producer, queues: Vec<String>) {
+		self.count = self.count.saturating_add(1);
+		count_in(&mut self.producers, producer);
+		for queue in queues {
+			count_in(&mut self.queues, queue);
+		}
+	}
+}
+
+/// Observations of unknown producers, for tests.
+#[cfg(test)]
+impl From<usize> for Counts {
+	fn from(count: usize) -> Counts {
+		Counts {
+			count,
+			producers: Vec::new(),
+			queues: Vec::new(),
+		}
+	}
+}
+
+/// How counts are inserted, read from the config once by the consumer.
+#[derive(Debug, Clone, Default)]
+pub struct InsertOptions {
+	/// Unit of the time buckets counts are also added to, if any.
+	pub bucket: Option<String>,
+	/// Stream counts with `copy` instead of an `unnest` upsert. Postgres only.
+	pub copy: bool,
+	/// Channel to notify of newly stored shapes. Postgres only.
+	pub notify_channel: Option<String>,
+}
+
+/// A shape stored on an exchange with its count across discriminators and envelopes.
+pub struct StoredShape {
+	pub key_digest: String,
+	pub key_paths: Vec<String>,
+	pub count: i64,
+}
+
+/// Similar shapes on an exchange, identified by the `key_digest` of their representative.
+pub struct Cluster {
+	pub id: String,
+	pub key_paths: Vec<String>,
+	pub shapes: i32,
+	pub count: i64,
+	/// `key_digest` of every shape in the cluster.
+	pub members: Vec<String>,
+}
+
+/// Maximum age in hours of shapes and time buckets, by default and per exchange. `0` keeps them
+/// forever. Parsed from a comma-separated list of `[exchange:]hours`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Retention {
+	pub default: u32,
+	pub exchanges: Vec<(String, u32)>,
+}
+
+impl Retention {
+	pub fn is_enabled(&self) -> bool {
+		self.default > 0 || self.exchanges.iter().any(|(_, hours)| *hours > 0)
+	}
+
+	/// Rules with a fixed age each, so that rows past it can be looked up in an index: one for every
+	/// exchange with a rule of its own, then the default for all other exchanges, as `None`.
+	pub fn passes(&self) -> Vec<(Option<&str>, u32)> {
+		let mut passes: Vec<(Option<&str>, u32)> = self
+			.exchanges
+			.iter()
+			.filter(|(_, hours)| *hours > 0)
+			.map(|(exchange, hours)| (Some(exchange.as_str()), *hours))
+			.collect();
+		if self.default > 0 {
+			passes.push((None, self.default));
+		}
+		passes
+	}
+
+	/// Exchanges the default doesn't apply to.
+	pub fn ruled_exchanges(&self) -> Vec<String> {
+		self.exchanges
+			.iter()
+			.map(|(exchange, _)| exchange.clone())
+			.collect()
+	}
+}
+
+impl FromStr for Retention {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut retention = Retention::default();
+		for rule in s.split(',').filter(|rule| !rule.is_empty()) {
+			let (exchange, hours) = match rule.rsplit_once(':') {
+				Some((exchange, hours)) => (Some(exchange), hours),
+				None => (None, rule),
+			};
+			let hours = hours
+				.parse::<u32>()
+				.map_err(|e| format!("invalid hours in {rule:?}: {e}"))?;
+			match exchange {
+				Some("") => return Err(format!("empty exchange in {rule:?}")),
+				Some(exchange) => retention.exchanges.push((exchange.to_string(), hours)),
+				None => retention.default = hours,
+			}
+		}
+		Ok(retention)
+	}
+}
+
+/// Shapes and time buckets on an exchange that are older than retention allows.
+pub struct Prunable {
+	pub vhost: String,
+	pub exchange: String,
+	pub shapes: i64,
+	pub buckets: i64,
+}
+
+/// Where observed shapes are persisted.
+pub trait Storage: Clone + Send + Sync + 'static {
+	/// Adds counts to the shapes and, if a bucket is set in `options`, to the time bucket of that
+	/// unit they're in, in a single transaction.
+	fn insert_counts(
+		&self,
+		counts: &HashMap<Payload, Counts>,
+		options: &InsertOptions,
+	) -> impl Future<Output = Result<(), sqlx::Error>> + Send;
+
+	/// Exchanges, as vhost and name, with shapes not assigned to a cluster yet.
+	fn unclustered_exchanges(
+		&self,
+	) -> impl Future<Output = Result<Vec<(String, String)>, sqlx::Error>> + Send;
+
+	/// Shapes on an exchange, most common first.
+	fn shapes(
+		&self,
+		vhost: &str,
+		exchange: &str,
+	) -> impl Future<Output = Result<Vec<StoredShape>, sqlx::Error>> + Send;
+
+	/// Replaces the clusters on an exchange and assigns their shapes to them.
+	fn replace_clusters(
+		&self,
+		vhost: &str,
+		exchange: &str,
+		clusters: &[Cluster],
+	) -> impl Future<Output = Result<(), sqlx::Error>> + Send;
+
+	/// Deletes up to `limit` shapes last seen longer ago than `retention` allows, along with their
+	/// time buckets. Returns the number of shapes deleted.
+	fn prune_shapes(
+		&self,
+		retention: &Retention,
+		limit: u32,
+	) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+
+	/// Deletes up to `limit` time buckets older than `retention` allows. Returns the number of
+	/// buckets deleted.
+	fn prune_buckets(
+		&self,
+		retention: &Retention,
+		limit: u32,
+	) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+
+	/// Deletes clusters no shapes belong to anymore. Returns the number of clusters deleted.
+	fn prune_clusters(&self) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+
+	/// What pruning would delete, per exchange.
+	fn prunable(
+		&self,
+		retention: &Retention,
+	) -> impl Future<Output = Result<Vec<Prunable>, sqlx::Error>> + Send;
+
+	/// Merges time buckets older than `after` hours into `bucket` wide ones. Returns the number of
+	/// buckets merged into.
+	fn downsample(
+		&self,
+		after: u32,
+		bucket: &str,
+	) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+}
+
+/// Storage backend selected by the scheme of the connection URL.
+#[derive(Clone)]
+pub enum Database {
+	Postgres(PgPool),
+	Sqlite(SqlitePool),
+}
+
+impl Storage for Database {
+	async fn insert_counts(
+		&self,
+		counts: &HashMap<Payload, Counts>,
+		options: &InsertOptions,
+	) -> Result<(), sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.insert_counts(counts, options).await,
+			Database::Sqlite(pool) => pool.insert_counts(counts, options).await,
+		}
+	}
+
+	async fn unclustered_exchanges(&self) -> Result<Vec<(String, String)>, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.unclustered_exchanges().await,
+			Database::Sqlite(pool) => pool.unclustered_exchanges().await,
+		}
+	}
+
+	async fn shapes(&self, vhost: &str, exchange: &str) -> Result<Vec<StoredShape>, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.shapes(vhost, exchange).await,
+			Database::Sqlite(pool) => pool.shapes(vhost, exchange).await,
+		}
+	}
+
+	async fn replace_clusters(
+		&self,
+		vhost: &str,
+		exchange: &str,
+		clusters: &[Cluster],
+	) -> Result<(), sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.replace_clusters(vhost, exchange, clusters).await,
+			Database::Sqlite(pool) => pool.replace_clusters(vhost, exchange, clusters).await,
+		}
+	}
+
+	async fn prune_shapes(&self, retention: &Retention, limit: u32) -> Result<u64, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.prune_shapes(retention, limit).await,
+			Database::Sqlite(pool) => pool.prune_shapes(retention, limit).await,
+		}
+	}
+
+	async fn prune_buckets(&self, retention: &Retention, limit: u32) -> Result<u64, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.prune_buckets(retention, limit).await,
+			Database::Sqlite(pool) => pool.prune_buckets(retention, limit).await,
+		}
+	}
+
+	async fn prune_clusters(&self) -> Result<u64, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.prune_clusters().await,
+			Database::Sqlite(pool) => pool.prune_clusters().await,
+		}
+	}
+
+	async fn prunable(&self, retention: &Retention) -> Result<Vec<Prunable>, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.prunable(retention).await,
+			Database::Sqlite(pool) => pool.prunable(retention).await,
+		}
+	}
+
+	async fn downsample(&self, after: u32, bucket: &str) -> Result<u64, sqlx::Error> {
+		match self {
+			Database::Postgres(pool) => pool.downsample(after, bucket).await,
+			Database::Sqlite(pool) => pool.downsample(after, bucket).await,
+		}
+	}
+}
+
+/// Warns of settings that only apply to Postgres being set along with SQLite.
+fn warn_postgres_only() {
+	let set = [
+		(
+			"ROBSERVER_DB_SCHEMA",
+			std::env::var("ROBSERVER_DB_SCHEMA").is_ok(),
+		),
+		("ROBSERVER_INSERT_METHOD", config::psql::get_copy()),
+		(
+			"ROBSERVER_NOTIFY_CHANNEL",
+			config::psql::get_notify_channel().is_some(),
+		),
+	];
+	for (var, is_set) in set {
+		if is_set {
+			warn!(var, "Ignoring a PostgreSQL-only setting with SQLite");
+		}
+	}
+}
+
+/// Connects to the database, SQLite for `sqlite:` URLs and Postgres otherwise. Migrations are
+/// applied if `ROBSERVER_MIGRATE` is set.
+pub async fn connect() -> Database {
+	let url = config::psql::get_url();
+	if url.starts_with("sqlite:") {
+		warn_postgres_only();
+		let pool = sqlite::connect(&url).await;
+		if config::psql::get_migrate() {
+			sqlite::migrate(&pool).await;
+		}
+		Database::Sqlite(pool)
+	} else {
+		let pool = postgres::connect(&url).await;
+		if config::psql::get_migrate() {
+			postgres::migrate(&pool).await;
+		}
+		Database::Postgres(pool)
+	}
+}
+
+/// Creates the database if needed and applies migrations.
+pub async fn migrate() {
+	let url = config::psql::get_url();
+	if url.starts_with("sqlite:") {
+		sqlite::migrate(&sqlite::connect(&url).await).await;
+	} else {
+		postgres::create_database(&url).await;
+		postgres::migrate(&postgres::connect(&url).await).await;
+	}
+}
+
+fn backoff() -> Backoff {
+	Backoff::new(
+		Duration::from_millis(config::psql::get_retry_min_delay()),
+		Duration::from_millis(config::psql::get_retry_max_delay()),
+	)
+}
+
+fn aggregate(
+	counts: &mut HashMap<Payload, Counts>,
+	delivered: &mut Option<u64>,
+	payloads: &mut Vec<Payload>,
+) {
+	for mut payload in payloads.drain(0..) {
+		if let Some(delivery_tag) = payload.delivery_tag.take() {
+			*delivered = (*delivered).max(Some(delivery_tag));
+		}
+		let producer = std::mem::take(&mut payload.producer);
+		let queues = std::mem::take(&mut payload.queues);
+		if let Some(c) = counts.get_mut(&payload) {
+			c.add(producer, queues);
+		} else {
+			let mut c = Counts::default();
+			c.add(producer, queues);
+			counts.insert(payload, c);
+		}
+	}
+}
+
+fn ack(acks: &mpsc::UnboundedSender<u64>, delivered: &mut Option<u64>) {
+	if let Some(delivery_tag) = delivered.take() {
+		if acks.send(delivery_tag).is_err() {
+			error!(delivery_tag, "Ack channel closed");
+		}
+	}
+}
+
+/// Inserts spooled counts, oldest first, until the spool is empty or a transient error occurs.
+async fn replay<S: Storage>(
+	storage: &S,
+	spool: &mut Spool,
+	options: &InsertOptions,
+) -> Result<(), sqlx::Error> {
+	loop {
+		let record = match spool.peek() {
+			Ok(Some(record)) => record,
+			Ok(None) => return Ok(()),
+			Err(error) => {
+				error!(?error, "Failed to read spooled counts");
+				return Ok(());
+			}
+		};
+
+		match serde_json::from_slice::<Vec<(Payload, Counts)>>(&record) {
+			Ok(counts) => {
+				let counts: HashMap<Payload, Counts> = counts.into_iter().collect();
+				match storage.insert_counts(&counts, options).await {
+					Ok(()) => info!(
+						shapes = counts.len(),
+						remaining_bytes = spool.size(),
+						"Replayed spooled counts"
+					),
+					Err(error) if retry::is_transient(&error) => return Err(error),
+					Err(error) => error!(
+						?error,
+						shapes = counts.len(),
+						observations = counts.values().map(|c| c.count).sum::<usize>(),
+						"Failed to insert spooled counts, dropping them"
+					),
+				}
+			}
+			Err(error) => error!(
+				?error,
+				bytes = record.len(),
+				"Discarding unreadable spooled counts"
+			),
+		}
+
+		if let Err(error) = spool.consume() {
+			error!(?error, "Failed to remove replayed counts from spool");
+		}
+	}
+}
+
+/// Writes counts to the spool. Returns whether they're taken care of, i.e. spooled or discarded.
+fn spool_counts(spool: &mut Spool, counts: &HashMap<Payload, Counts>) -> bool {
+	let record =
+		serde_json::to_vec(&counts.iter().collect::<Vec<_>>()).expect("Failed to serialize counts");
+	match spool.append(&record) {
+		Ok(true) => {
+			info!(
+				shapes = counts.len(),
+				bytes = record.len(),
+				spool_bytes = spool.size(),
+				"Spooled counts"
+			);
+			true
+		}
+		Ok(false) => {
+			error!(
+				shapes = counts.len(),
+				observations = counts.values().map(|c| c.count).sum::<usize>(),
+				spool_bytes = spool.size(),
+				"Spool full, discarding counts"
+			);
+			true
+		}
+		Err(error) => {
+			error!(?error, "Failed to spool counts, keeping them in memory");
+			false
+		}
+	}
+}
+
+/// Writes aggregated counts to the database. While the database is unavailable, counts are
+/// written to the spool, if configured, to be replayed in order once it's back. Otherwise
+/// payloads keep being aggregated into the pending counts until `ROBSERVER_MAX_PENDING_SHAPES`
+/// distinct shapes are reached, after which the channel fills up and applies backpressure to the
+/// listener.
+///
+/// The highest delivery tag among the payloads is sent on `acks` once their counts are
+/// committed, spooled, or deliberately dropped.
+pub async fn consumer<S: Storage>(
+	storage: S,
+	mut rx: mpsc::Receiver<Payload>,
+	acks: mpsc::UnboundedSender<u64>,
+) {
+	let query_delay = config::psql::get_query_delay();
+	let buffer_size = config::psql::get_max_query_size();
+	let max_pending = config::psql::get_max_pending_shapes();
+	let options = InsertOptions {
+		bucket: config::history::get_bucket(),
+		copy: config::psql::get_copy(),
+		notify_channel: config::psql::get_notify_channel(),
+	};
+	let mut backoff = backoff();
+	let mut spool = config::psql::get_spool_dir().map(|dir| {
+		Spool::open(
+			Path::new(&dir),
+			config::psql::get_spool_max_size(),
+			config::psql::get_spool_segment_size(),
+		)
+		.expect("Failed to open spool")
+	});
+	let mut to_handle: Vec<Payload> = Vec::with_capacity(buffer_size);
+	let mut pending: HashMap<Payload, Counts> = HashMap::new();
+	let mut delivered: Option<u64> = None;
+	let mut closed = false;
+
+	loop {
+		let mut x = 0;
+		let spooled = spool.as_ref().is_some_and(|spool| !spool.is_empty());
+		if pending.is_empty() && !spooled {
+			if closed {
+				break;
+			}
+			x = rx.recv_many(&mut to_handle, buffer_size).await;
+			if x == 0 {
+				error!("Channel closed");
+				break;
+			}
+			info!(len = x, "Processing items");
+			aggregate(&mut pending, &mut delivered, &mut to_handle);
+		}
+
+		let mut result = Ok(());
+		if let Some(spool) = spool.as_mut() {
+			result = replay(&storage, spool, &options).await;
+		}
+		if result.is_ok() && !pending.is_empty() {
+			result = storage.insert_counts(&pending, &options).await;
+		}
+
+		match result {
+			Ok(()) => {
+				if backoff.attempts() > 0 {
+					info!(
+						attempts = backoff.attempts(),
+						shapes = pending.len(),
+						"Recovered from database errors"
+					);
+					backoff.reset();
+				}
+				pending.clear();
+				ack(&acks, &mut delivered);
+			}
+			Err(error) if retry::is_transient(&error) => {
+				let delay = backoff.next_delay();
+				warn!(
+					?error,
+					attempts = backoff.attempts(),
+					retry_in_ms = delay.as_millis(),
+					shapes = pending.len(),
+					observations = pending.values().map(|c| c.count).sum::<usize>(),
+					"Failed to insert counts, retrying"
+				);
+				if let Some(spool) = spool.as_mut() {
+					if !pending.is_empty() && spool_counts(spool, &pending) {
+						pending.clear();
+						ack(&acks, &mut delivered);
+					}
+				}
+				// Keep aggregating while waiting, within budget
+				let sleep = tokio::time::sleep(delay);
+				tokio::pin!(sleep);
+				loop {
+					tokio::select! {
+						_ = &mut sleep => break,
+						received = rx.recv_many(&mut to_handle, buffer_size),
+							if !closed && pending.len() < max_pending =>
+						{
+							if received == 0 {
+								error!("Channel closed");
+								closed = true;
+							}
+							aggregate(&mut pending, &mut delivered, &mut to_handle);
+						}
+					}
+				}
+				if pending.len() >= max_pending {
+					warn!(shapes = pending.len(), "Pending counts budget reached");
+				}
+				continue;
+			}
+			Err(error) => {
+				error!(
+					?error,
+					shapes = pending.len(),
+					observations = pending.values().map(|c| c.count).sum::<usize>(),
+					"Failed to insert counts, dropping them"
+				);
+				backoff.reset();
+				pending.clear();
+				ack(&acks, &mut delivered);
+			}
+		}
+
+		if x < buffer_size {
+			// The process is IO bound, let's save that IO for the MQ end
+			tokio::time::sleep(Duration::from_millis(query_delay)).await;
+			info!(ms = query_delay, "Sleeping");
+		}
+	}
+
+	info!("DB worker finished");
+}
+
+async fn update_clusters<S: Storage>(
+	storage: &S,
+	vhost: &str,
+	exchange: &str,
+	threshold: f64,
+) -> Result<usize, sqlx::Error> {
+	// Most common shapes first to have them represent their clusters
+	let shapes = storage.shapes(vhost, exchange).await?;
+	let key_paths: Vec<Vec<String>> = shapes.iter().map(|s| s.key_paths.clone()).collect();
+	let assignments = cluster::cluster(&key_paths, threshold);
+
+	let mut clusters: BTreeMap<usize, Cluster> = BTreeMap::new();
+	for (shape, representative) in assignments.into_iter().enumerate() {
+		let cluster = clusters.entry(representative).or_insert_with(|| Cluster {
+			id: shapes[representative].key_digest.clone(),
+			key_paths: shapes[representative].key_paths.clone(),
+			shapes: 0,
+			count: 0,
+			members: Vec::new(),
+		});
+		cluster.shapes += 1;
+		cluster.count = cluster.count.saturating_add(shapes[shape].count);
+		cluster.members.push(shapes[shape].key_digest.clone());
+	}
+	let clusters: Vec<Cluster> = clusters.into_values().collect();
+	storage.replace_clusters(vhost, exchange, &clusters).await?;
+
+	Ok(clusters.len())
+}
+
+/// Periodically regroups shapes into clusters on exchanges where new shapes have appeared.
+pub async fn clusterer<S: Storage>(storage: S) {
+	let threshold = config::cluster::get_threshold();
+	let mut interval =
+		tokio::time::interval(Duration::from_millis(config::cluster::get_interval()));
+
+	loop {
+		interval.tick().await;
+		let exchanges = match storage.unclustered_exchanges().await {
+			Ok(exchanges) => exchanges,
+			Err(error) => {
+				error!(?error, "Failed to find shapes to cluster");
+				continue;
+			}
+		};
+
+		for (vhost, exchange) in exchanges {
+			match update_clusters(&storage, &vhost, &exchange, threshold).await {
+				Ok(clusters) => info!(vhost, exchange, clusters, "Updated clusters"),
+				Err(error) => error!(?error, vhost, exchange, "Failed to update clusters"),
+			}
+		}
+	}
+}
+
+/// Periodically merges time buckets older than the configured age into coarser ones.
+pub async fn downsampler<S: Storage>(storage: S) {
+	let after = config::history::get_downsample_after();
+	let bucket = config::history::get_downsample_bucket();
+	let mut interval = tokio::time::interval(Duration::from_secs(DOWNSAMPLE_INTERVAL));
+
+	loop {
+		interval.tick().await;
+		match storage.downsample(after, &bucket).await {
+			Ok(buckets) => info!(buckets, bucket, "Downsampled counts"),
+			Err(error) => error!(?error, "Failed to downsample counts"),
+		}
+	}
+}
+
+/// Deletes in batches of `limit` until there's nothing left to delete, or a batch deletes nothing.
+async fn prune_batches<F, Fut>(limit: u32, mut prune: F) -> Result<u64, sqlx::Error>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<u64, sqlx::Error>>,
+{
+	let mut total = 0;
+	loop {
+		let deleted = prune().await?;
+		total += deleted;
+		if deleted == 0 || deleted < u64::from(limit) {
+			return Ok(total);
+		}
+	}
+}
+
+/// Periodically deletes shapes and time buckets older than retention allows, in small batches to
+/// keep locks short. In dry-run mode, only logs what would be deleted.
+pub async fn pruner<S: Storage>(storage: S) {
+	let retention = config::retention::get_retention();
+	let limit = config::retention::get_batch_size();
+	let dry_run = config::retention::get_dry_run();
+	let mut interval =
+		tokio::time::interval(Duration::from_millis(config::retention::get_interval()));
+
+	loop {
+		interval.tick().await;
+		if dry_run {
+			match storage.prunable(&retention).await {
+				Ok(prunable) => {
+					for p in prunable {
+						info!(
+							vhost = p.vhost,
+							exchange = p.exchange,
+							shapes = p.shapes,
+							buckets = p.buckets,
+							"Would prune"
+						);
+					}
+				}
+				Err(error) => error!(?error, "Failed to find what to prune"),
+			}
+			continue;
+		}
+
+		let shapes = prune_batches(limit, || storage.prune_shapes(&retention, limit)).await;
+		let buckets = prune_batches(limit, || storage.prune_buckets(&retention, limit)).await;
+		let clusters = storage.prune_clusters().await;
+		match (shapes, buckets, clusters) {
+			(Ok(shapes), Ok(buckets), Ok(clusters)) => {
+				info!(shapes, buckets, clusters, "Pruned")
+			}
+			(shapes, buckets, clusters) => error!(?shapes, ?buckets, ?clusters, "Failed to prune"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn count_conversion() {
+		assert_eq!(to_count(0), 0);
+		assert_eq!(to_count(2_147_483_648), 2_147_483_648);
+		assert_eq!(to_count(usize::MAX), i64::MAX);
+	}
+
+	fn payload(data: &str, app_id: &str) -> Payload {
+		let mut payload = Payload::new(
+			data.as_bytes().to_vec(),
+			String::from("/"),
+			String::from("robserver.test"),
+			String::new(),
+		);
+		payload.producer.app_id = app_id.to_string();
+		payload
+	}
+
+	#[test]
+	fn aggregation() {
+		let mut counts = HashMap::new();
+		let mut delivered = None;
+		let mut payloads = vec![
+			payload(r#"{"a":1}"#, "billing"),
+			payload(r#"{"a":2}"#, "orders"),
+			payload(r#"{"a":3}"#, "billing"),
+			payload(r#"{"b":1}"#, ""),
+		];
+		payloads[2].delivery_tag = Some(7);
+		payloads[0].queues = vec![String::from("invoices"), String::from("audit")];
+		payloads[1].queues = vec![String::from("audit")];
+		aggregate(&mut counts, &mut delivered, &mut payloads);
+
+		assert_eq!(delivered, Some(7));
+		assert_eq!(counts.len(), 2);
+		let a = &counts[&payload(r#"{"a":1}"#, "")];
+		assert_eq!(a.count, 3);
+		let producers: Vec<(&str, usize)> = a
+			.producers
+			.iter()
+			.map(|(producer, count)| (producer.app_id.as_str(), *count))
+			.collect();
+		assert_eq!(producers, vec![("billing", 2), ("orders", 1)]);
+		assert_eq!(
+			a.queues,
+			vec![(String::from("invoices"), 1), (String::from("audit"), 2)]
+		);
+		assert!(counts
+			.keys()
+			.all(|payload| payload.producer == Producer::default() && payload.queues.is_empty()));
+	}
+
+	#[test]
+	fn spooled_counts() {
+		let mut counts = Counts::default();
+		counts.add(
+			Producer {
+				app_id: String::from("billing"),
+				user_id: String::from("billing-svc"),
+			},
+			vec![String::from("invoices")],
+		);
+		let spooled = serde_json::to_string(&counts).unwrap();
+		assert_eq!(serde_json::from_str::<Counts>(&spooled).unwrap(), counts);
+	}
+
+	#[tokio::test]
+	async fn prune_batches_until_done() {
+		let calls = std::cell::Cell::new(0);
+		let remaining = std::cell::Cell::new(25);
+		let prune = |limit: u32| {
+			calls.set(calls.get() + 1);
+			let deleted = remaining.get().min(u64::from(limit));
+			remaining.set(remaining.get() - deleted);
+			async move { Ok(deleted) }
+		};
+		assert_eq!(prune_batches(10, || prune(10)).await.unwrap(), 25);
+		assert_eq!(calls.get(), 3);
+
+		// Nothing is deleted with a batch size of 0, which must not loop forever
+		calls.set(0);
+		remaining.set(25);
+		assert_eq!(prune_batches(0, || prune(0)).await.unwrap(), 0);
+		assert_eq!(calls.get(), 1);
+	}
+
+	#[test]
+	fn retention() {
+		assert_eq!("".parse::<Retention>(), Ok(Retention::default()));
+		assert!(!Retention::default().is_enabled());
+		assert_eq!(
+			"720,amq.topic:24,logs:0".parse::<Retention>(),
+			Ok(Retention {
+				default: 720,
+				exchanges: vec![(String::from("amq.topic"), 24), (String::from("logs"), 0)],
+			})
+		);
+		assert!("0,amq.topic:24".parse::<Retention>().unwrap().is_enabled());
+		assert!(!"0,amq.topic:0".parse::<Retention>().unwrap().is_enabled());
+		assert_eq!(
+			"720,amq.topic:24,logs:0"
+				.parse::<Retention>()
+				.unwrap()
+				.passes(),
+			vec![(Some("amq.topic"), 24), (None, 720)]
+		);
+		assert_eq!(
+			"0,logs:12".parse::<Retention>().unwrap().passes(),
+			vec![(Some("logs"), 12)]
+		);
+		assert_eq!(
+			"a:b:1".parse::<Retention>().unwrap().exchanges,
+			vec![(String::from("a:b"), 1)]
+		);
+
+		assert!("amq.topic:".parse::<Retention>().is_err());
+		assert!(":24".parse::<Retention>().is_err());
+		assert!("-1".parse::<Retention>().is_err());
+	}
+}
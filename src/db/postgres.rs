@@ -0,0 +1,1686 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde_json::Value;
+use sqlx::migrate::{MigrateDatabase, Migration};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::Executor;
+use sqlx::{types::BigDecimal, PgPool, Postgres};
+use tracing::{info, warn};
+
+use super::copy::CopyEncoder;
+use super::{
+	retry, to_count, Cluster, Counts, InsertOptions, Prunable, Retention, Storage, StoredShape,
+};
+use crate::config;
+use crate::payload::{Data, Payload};
+
+/// Chunks the staged shapes are streamed in.
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/// Same columns as the `unnest` in `insert_counts`, for batches streamed with `COPY`. Numeric ids
+/// are staged as text as their binary representation isn't worth encoding by hand.
+const CREATE_STAGING: &str = r#"
+	create temp table entity_staging (
+		id text,
+		vhost text,
+		exchange text,
+		payload jsonb,
+		raw_payload text,
+		routing_key text,
+		count bigint,
+		ignore_rules text,
+		key_paths text,
+		key_digest text,
+		discriminator text,
+		cloudevent_type text,
+		cloudevent_source text,
+		envelope_id text,
+		normalized_id text,
+		normalized_keys boolean,
+		truncated_count bigint
+	) on commit drop
+"#;
+
+/// Same upsert as in `insert_counts`, from the staging table. Not checked at compile time as the
+/// staging table only exists within the transaction.
+const MERGE_STAGING: &str = r#"
+	insert into entity as e (
+		id,
+		vhost,
+		exchange,
+		payload,
+		raw_payload,
+		routing_key,
+		count,
+		ignore_rules,
+		key_paths,
+		key_digest,
+		discriminator,
+		cloudevent_type,
+		cloudevent_source,
+		envelope_id,
+		normalized_id,
+		normalized_keys,
+		truncated_count
+	)
+	select
+		id::numeric,
+		vhost,
+		exchange,
+		payload,
+		raw_payload,
+		routing_key,
+		count,
+		string_to_array(ignore_rules, ','),
+		key_paths::text[],
+		key_digest::uuid,
+		discriminator,
+		cloudevent_type,
+		cloudevent_source,
+		envelope_id::numeric,
+		normalized_id::numeric,
+		normalized_keys,
+		truncated_count
+	from entity_staging
+	on conflict
+		on constraint entity_pkey
+			do update set
+				count = add_counts(e.count, EXCLUDED.count),
+				truncated_count = add_counts(e.truncated_count, EXCLUDED.truncated_count),
+				last_seen_at = now(),
+				ignore_rules = EXCLUDED.ignore_rules,
+				normalized_id = EXCLUDED.normalized_id,
+				normalized_keys = EXCLUDED.normalized_keys
+	returning
+		(xmax = 0) as inserted,
+		id,
+		vhost,
+		exchange,
+		routing_key,
+		key_digest::text
+"#;
+
+/// Array literal of `values`, for arrays of arrays, which `unnest` would flatten, passed as one
+/// text per row and cast back with `::text[]`.
+fn text_array(values: &[String]) -> String {
+	let elements: Vec<String> = values
+		.iter()
+		.map(|value| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")))
+		.collect();
+	format!("{{{}}}", elements.join(","))
+}
+
+/// A shape inserted into or updated in `entity`.
+#[derive(sqlx::FromRow)]
+struct Upserted {
+	inserted: bool,
+	id: BigDecimal,
+	vhost: String,
+	exchange: String,
+	routing_key: Option<String>,
+	key_digest: String,
+}
+
+/// Inserts or updates counts with a single `unnest` upsert, or with `copy` by streaming them into
+/// a staging table merged with one upsert.
+async fn insert_counts(
+	conn: &PgPool,
+	counts: &HashMap<Payload, Counts>,
+	bucket: Option<&str>,
+	copy: bool,
+	notify_channel: Option<&str>,
+) -> Result<(), sqlx::Error> {
+	let mut id = Vec::with_capacity(counts.len());
+	let mut vhost = Vec::with_capacity(counts.len());
+	let mut exchange = Vec::with_capacity(counts.len());
+	let mut json = Vec::with_capacity(counts.len());
+	let mut raw: Vec<Option<String>> = Vec::with_capacity(counts.len());
+	let mut routing_key: Vec<String> = Vec::with_capacity(counts.len());
+	let mut discriminator: Vec<String> = Vec::with_capacity(counts.len());
+	let mut cloudevent_type: Vec<String> = Vec::with_capacity(counts.len());
+	let mut cloudevent_source: Vec<String> = Vec::with_capacity(counts.len());
+	let mut envelope_id = Vec::with_capacity(counts.len());
+	let mut ignore_rules: Vec<String> = Vec::with_capacity(counts.len());
+	let mut key_paths: Vec<String> = Vec::with_capacity(counts.len());
+	let mut key_digest: Vec<String> = Vec::with_capacity(counts.len());
+	let mut normalized_id = Vec::with_capacity(counts.len());
+	let mut normalized_keys = Vec::with_capacity(counts.len());
+	let mut count = Vec::with_capacity(counts.len());
+	let mut truncated_count = Vec::with_capacity(counts.len());
+	// Producers of the shape at `producer_shape` in the arrays above, starting from 1
+	let mut producer_shape: Vec<i64> = Vec::new();
+	let mut app_id: Vec<String> = Vec::new();
+	let mut user_id: Vec<String> = Vec::new();
+	let mut producer_count = Vec::new();
+	// Likewise queues routed to
+	let mut queue_shape: Vec<i64> = Vec::new();
+	let mut queue: Vec<String> = Vec::new();
+	let mut queue_count = Vec::new();
+	for (p, counts) in counts {
+		let to_add = counts.count;
+		if to_add == 0 {
+			continue;
+		}
+		id.push(BigDecimal::from(p.id));
+		vhost.push(p.vhost.clone());
+		exchange.push(p.exchange.clone());
+		routing_key.push(p.routing_key.clone());
+		discriminator.push(p.discriminator.clone());
+		match &p.envelope {
+			Some(envelope) => {
+				cloudevent_type.push(envelope.r#type.clone());
+				cloudevent_source.push(envelope.source.clone());
+				envelope_id.push(BigDecimal::from(envelope.id));
+			}
+			None => {
+				cloudevent_type.push(String::new());
+				cloudevent_source.push(String::new());
+				envelope_id.push(BigDecimal::from(0));
+			}
+		}
+		// Rules never contain commas as they're configured as a comma-separated list
+		ignore_rules.push(p.ignore_rules.join(","));
+		key_paths.push(text_array(&p.key_paths));
+		key_digest.push(super::key_digest(&p.key_paths));
+		normalized_id.push(BigDecimal::from(p.normalized_id));
+		normalized_keys.push(p.normalized_keys);
+		match &p.content {
+			Data::Json(value) => {
+				json.push(Some(value.clone()));
+				raw.push(None);
+			}
+			Data::Raw(value) => {
+				json.push(None);
+				raw.push(String::from_utf8(value.clone()).ok());
+			}
+		}
+		count.push(to_count(to_add));
+		truncated_count.push(if p.truncated { to_count(to_add) } else { 0 });
+		for (producer, count) in &counts.producers {
+			producer_shape.push(id.len() as i64);
+			app_id.push(producer.app_id.clone());
+			user_id.push(producer.user_id.clone());
+			producer_count.push(to_count(*count));
+		}
+		for (name, count) in &counts.queues {
+			queue_shape.push(id.len() as i64);
+			queue.push(name.clone());
+			queue_count.push(to_count(*count));
+		}
+	}
+	info!(len = id.len(), "Inserting/updating counts");
+	let mut tx = conn.begin().await?;
+	let rows = if copy {
+		tx.execute(CREATE_STAGING).await?;
+		let mut copy_in = tx
+			.copy_in_raw("copy entity_staging from stdin (format binary)")
+			.await?;
+		let mut staged = CopyEncoder::new();
+		for (i, id) in id.iter().enumerate() {
+			staged.row(17);
+			staged.text(&id.to_string());
+			staged.text(&vhost[i]);
+			staged.text(&exchange[i]);
+			match &json[i] {
+				Some(value) => staged.jsonb(value),
+				None => staged.null(),
+			}
+			match &raw[i] {
+				Some(value) => staged.text(value),
+				None => staged.null(),
+			}
+			staged.text(&routing_key[i]);
+			staged.bigint(count[i]);
+			staged.text(&ignore_rules[i]);
+			staged.text(&key_paths[i]);
+			staged.text(&key_digest[i]);
+			staged.text(&discriminator[i]);
+			staged.text(&cloudevent_type[i]);
+			staged.text(&cloudevent_source[i]);
+			staged.text(&envelope_id[i].to_string());
+			staged.text(&normalized_id[i].to_string());
+			staged.boolean(normalized_keys[i]);
+			staged.bigint(truncated_count[i]);
+			if staged.len() >= COPY_CHUNK_SIZE {
+				copy_in.send(staged.take()).await?;
+			}
+		}
+		copy_in.send(staged.finish()).await?;
+		copy_in.finish().await?;
+
+		sqlx::query_as::<_, Upserted>(MERGE_STAGING)
+			.fetch_all(&mut *tx)
+			.await?
+	} else {
+		sqlx::query_as!(
+			Upserted,
+			r#"
+			insert into entity as e (
+				id,
+				vhost,
+				exchange,
+				payload,
+				raw_payload,
+				routing_key,
+				count,
+				ignore_rules,
+				key_paths,
+				key_digest,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				normalized_id,
+				normalized_keys,
+				truncated_count
+			)
+			select
+				id,
+				vhost,
+				exchange,
+				payload,
+				raw_payload,
+				routing_key,
+				count,
+				string_to_array(ignore_rules, ','),
+				key_paths::text[],
+				key_digest,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				normalized_id,
+				normalized_keys,
+				truncated_count
+			from (
+				select
+					unnest($1::numeric[]) as id,
+					unnest($2::text[]) as vhost,
+					unnest($3::text[]) as exchange,
+					unnest($4::jsonb[]) as payload,
+					unnest($5::text[]) as raw_payload,
+					unnest($6::text[]) as routing_key,
+					unnest($7::bigint[]) as count,
+					unnest($8::text[]) as ignore_rules,
+					unnest($9::text[]) as key_paths,
+					unnest($10::uuid[]) as key_digest,
+					unnest($11::text[]) as discriminator,
+					unnest($12::text[]) as cloudevent_type,
+					unnest($13::text[]) as cloudevent_source,
+					unnest($14::numeric[]) as envelope_id,
+					unnest($15::numeric[]) as normalized_id,
+					unnest($16::boolean[]) as normalized_keys,
+					unnest($17::bigint[]) as truncated_count
+			) as new
+			on conflict
+				on constraint entity_pkey
+					do update set
+						count = add_counts(e.count, EXCLUDED.count),
+						truncated_count = add_counts(e.truncated_count, EXCLUDED.truncated_count),
+						last_seen_at = now(),
+						ignore_rules = EXCLUDED.ignore_rules,
+						normalized_id = EXCLUDED.normalized_id,
+						normalized_keys = EXCLUDED.normalized_keys
+			returning
+				(xmax = 0) as "inserted!",
+				id,
+				vhost,
+				exchange,
+				routing_key,
+				key_digest::text as "key_digest!"
+		"#,
+			&id[..],
+			&vhost[..],
+			&exchange[..],
+			&json[..] as &[Option<Value>],
+			&raw[..] as &[Option<String>],
+			&routing_key[..],
+			&count[..],
+			&ignore_rules[..],
+			&key_paths[..],
+			&key_digest as &[String],
+			&discriminator[..],
+			&cloudevent_type[..],
+			&cloudevent_source[..],
+			&envelope_id[..],
+			&normalized_id[..],
+			&normalized_keys[..],
+			&truncated_count[..],
+		)
+		.fetch_all(&mut *tx)
+		.await?
+	};
+
+	// Sent on commit, so listeners never hear of shapes that weren't stored
+	if let Some(channel) = notify_channel {
+		let created: Vec<_> = rows.into_iter().filter(|row| row.inserted).collect();
+		if !created.is_empty() {
+			sqlx::query!(
+				r#"
+				select pg_notify($1, json_build_object(
+					'vhost', vhost,
+					'exchange', exchange,
+					'routing_key', routing_key,
+					'id', id,
+					'key_digest', key_digest
+				)::text)
+				from unnest($2::text[], $3::text[], $4::text[], $5::numeric[], $6::text[])
+					as new(vhost, exchange, routing_key, id, key_digest)
+			"#,
+				channel,
+				&created
+					.iter()
+					.map(|row| row.vhost.clone())
+					.collect::<Vec<_>>(),
+				&created
+					.iter()
+					.map(|row| row.exchange.clone())
+					.collect::<Vec<_>>(),
+				&created
+					.iter()
+					.map(|row| row.routing_key.clone())
+					.collect::<Vec<_>>() as &[Option<String>],
+				&created.iter().map(|row| row.id.clone()).collect::<Vec<_>>(),
+				&created
+					.iter()
+					.map(|row| row.key_digest.clone())
+					.collect::<Vec<_>>(),
+			)
+			.execute(&mut *tx)
+			.await?;
+		}
+	}
+
+	sqlx::query!(
+		r#"
+		insert into entity_producer as p (
+			id,
+			key_digest,
+			vhost,
+			exchange,
+			discriminator,
+			cloudevent_type,
+			cloudevent_source,
+			envelope_id,
+			app_id,
+			user_id,
+			count
+		)
+		select
+			s.id,
+			s.key_digest,
+			s.vhost,
+			s.exchange,
+			s.discriminator,
+			s.cloudevent_type,
+			s.cloudevent_source,
+			s.envelope_id,
+			new.app_id,
+			new.user_id,
+			new.count
+		from unnest($1::bigint[], $2::text[], $3::text[], $4::bigint[])
+			as new(shape, app_id, user_id, count)
+		join unnest(
+			$5::numeric[],
+			$6::uuid[],
+			$7::text[],
+			$8::text[],
+			$9::text[],
+			$10::text[],
+			$11::text[],
+			$12::numeric[]
+		) with ordinality
+			as s(
+				id,
+				key_digest,
+				vhost,
+				exchange,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				shape
+			)
+			using (shape)
+		on conflict
+			on constraint entity_producer_pkey
+				do update set
+					count = add_counts(p.count, EXCLUDED.count),
+					last_seen_at = now()
+	"#,
+		&producer_shape[..],
+		&app_id[..],
+		&user_id[..],
+		&producer_count[..],
+		&id[..],
+		&key_digest as &[String],
+		&vhost[..],
+		&exchange[..],
+		&discriminator[..],
+		&cloudevent_type[..],
+		&cloudevent_source[..],
+		&envelope_id[..],
+	)
+	.execute(&mut *tx)
+	.await?;
+
+	sqlx::query!(
+		r#"
+		insert into entity_queue as q (
+			id,
+			key_digest,
+			vhost,
+			exchange,
+			discriminator,
+			cloudevent_type,
+			cloudevent_source,
+			envelope_id,
+			queue,
+			count
+		)
+		select
+			s.id,
+			s.key_digest,
+			s.vhost,
+			s.exchange,
+			s.discriminator,
+			s.cloudevent_type,
+			s.cloudevent_source,
+			s.envelope_id,
+			new.queue,
+			new.count
+		from unnest($1::bigint[], $2::text[], $3::bigint[]) as new(shape, queue, count)
+		join unnest(
+			$4::numeric[],
+			$5::uuid[],
+			$6::text[],
+			$7::text[],
+			$8::text[],
+			$9::text[],
+			$10::text[],
+			$11::numeric[]
+		) with ordinality
+			as s(
+				id,
+				key_digest,
+				vhost,
+				exchange,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				shape
+			)
+			using (shape)
+		on conflict
+			on constraint entity_queue_pkey
+				do update set
+					count = add_counts(q.count, EXCLUDED.count),
+					last_seen_at = now()
+	"#,
+		&queue_shape[..],
+		&queue[..],
+		&queue_count[..],
+		&id[..],
+		&key_digest as &[String],
+		&vhost[..],
+		&exchange[..],
+		&discriminator[..],
+		&cloudevent_type[..],
+		&cloudevent_source[..],
+		&envelope_id[..],
+	)
+	.execute(&mut *tx)
+	.await?;
+
+	if let Some(bucket) = bucket {
+		sqlx::query!(
+			r#"
+			insert into entity_counts as c (
+				id,
+				key_digest,
+				vhost,
+				exchange,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				bucket,
+				count
+			)
+			select
+				id,
+				key_digest,
+				vhost,
+				exchange,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				date_trunc($1, now()),
+				count
+			from (
+				select
+					unnest($2::numeric[]) as id,
+					unnest($3::uuid[]) as key_digest,
+					unnest($4::text[]) as vhost,
+					unnest($5::text[]) as exchange,
+					unnest($6::text[]) as discriminator,
+					unnest($7::text[]) as cloudevent_type,
+					unnest($8::text[]) as cloudevent_source,
+					unnest($9::numeric[]) as envelope_id,
+					unnest($10::bigint[]) as count
+			) as new
+			on conflict
+				on constraint entity_counts_pkey
+					do update set count = add_counts(c.count, EXCLUDED.count)
+		"#,
+			bucket,
+			&id[..],
+			&key_digest as &[String],
+			&vhost[..],
+			&exchange[..],
+			&discriminator[..],
+			&cloudevent_type[..],
+			&cloudevent_source[..],
+			&envelope_id[..],
+			&count[..],
+		)
+		.execute(&mut *tx)
+		.await?;
+	}
+
+	// Flag shapes sharing an id with a different key set. Only newly detected ones are returned.
+	let collisions = sqlx::query!(
+		r#"
+		update entity
+		set collision = true
+		where
+			not collision
+			and (id, vhost, exchange) in (
+				select id, vhost, exchange
+				from entity
+				where id = any($1::numeric[])
+				group by id, vhost, exchange
+				having count(distinct key_digest) > 1
+			)
+		returning id, vhost, exchange, discriminator, key_paths
+	"#,
+		&id[..],
+	)
+	.fetch_all(&mut *tx)
+	.await?;
+	for row in collisions {
+		warn!(
+			id = %row.id,
+			vhost = row.vhost,
+			exchange = row.exchange,
+			discriminator = row.discriminator,
+			key_paths = ?row.key_paths,
+			"Shape id collision"
+		);
+	}
+
+	tx.commit().await
+}
+
+/// Schema the migrations are written for.
+const MIGRATIONS_SCHEMA: &str = "data";
+
+/// Connects to Postgres, retrying as long as the failure is transient, e.g. while the database
+/// is still starting. The configured schema is the search path, which unqualified names in
+/// queries resolve against.
+pub async fn connect(url: &str) -> PgPool {
+	let options = PgConnectOptions::from_str(url)
+		.expect("invalid Postgres URL")
+		.options([("search_path", config::psql::get_schema())]);
+	let mut backoff = super::backoff();
+	loop {
+		info!("Connecting...");
+		let result = PgPoolOptions::new()
+			.after_connect(|conn, _meta| {
+				Box::pin(async move {
+					conn.execute("SET application_name = 'robserver';").await?;
+
+					Ok(())
+				})
+			})
+			.connect_with(options.clone())
+			.await;
+		match result {
+			Ok(pool) => {
+				info!("Connected");
+				return pool;
+			}
+			Err(error) if retry::is_transient(&error) => {
+				let delay = backoff.next_delay();
+				warn!(
+					?error,
+					attempts = backoff.attempts(),
+					retry_in_ms = delay.as_millis(),
+					"Failed to connect to Postgres"
+				);
+				tokio::time::sleep(delay).await;
+			}
+			Err(error) => panic!("Failed to connect to Postgres: {error}"),
+		}
+	}
+}
+
+/// Creates the database if it doesn't exist yet.
+pub async fn create_database(url: &str) {
+	if !Postgres::database_exists(url)
+		.await
+		.expect("Failed to check if the database exists")
+	{
+		match Postgres::create_database(url).await {
+			Ok(()) => info!("Created database"),
+			// Another replica got there first
+			Err(_) if Postgres::database_exists(url).await.unwrap_or(false) => {}
+			Err(error) => panic!("Failed to create the database: {error}"),
+		}
+	}
+}
+
+/// Replaces references to the schema the migrations are written for with `schema`: its
+/// `create schema` statement and names qualified with it. Comments, string literals and quoted
+/// identifiers are kept as they are, while function bodies in dollar quotes are code like any other.
+fn in_schema(sql: &str, schema: &str) -> String {
+	let sql = sql.replace(
+		&format!("create schema {MIGRATIONS_SCHEMA};"),
+		&format!("create schema if not exists {schema};"),
+	);
+	let prefix = format!("{MIGRATIONS_SCHEMA}.");
+	let is_identifier = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+	let mut result = String::with_capacity(sql.len());
+	let mut rest = sql.as_str();
+	let mut previous = None;
+	while let Some(c) = rest.chars().next() {
+		let len = if rest.starts_with("--") {
+			rest.find('\n').unwrap_or(rest.len())
+		} else if rest.starts_with("/*") {
+			rest.find("*/").map_or(rest.len(), |i| i + 2)
+		} else if c == '\'' || c == '"' {
+			rest[1..].find(c).map_or(rest.len(), |i| i + 2)
+		} else if rest.starts_with(&prefix) && !previous.is_some_and(is_identifier) {
+			result.push_str(schema);
+			result.push('.');
+			rest = &rest[prefix.len()..];
+			previous = Some('.');
+			continue;
+		} else {
+			c.len_utf8()
+		};
+		result.push_str(&rest[..len]);
+		previous = rest[..len].chars().next_back();
+		rest = &rest[len..];
+	}
+	result
+}
+
+/// Applies the migrations embedded from `migrations/` that haven't been applied yet to the
+/// configured schema. The `data` schema keeps track of applied
+/// migrations in `public._sqlx_migrations`, as it always has, any other schema in a
+/// `_sqlx_migrations` table of its own.
+pub async fn migrate(pool: &PgPool) {
+	info!("Migrating...");
+	let schema = config::psql::get_schema();
+	let mut migrator = sqlx::migrate!();
+	if schema != MIGRATIONS_SCHEMA {
+		// Checksums are kept, applied migrations don't differ by schema
+		migrator.migrations = migrator
+			.migrations
+			.iter()
+			.map(|migration| Migration {
+				sql: Cow::Owned(in_schema(&migration.sql, &schema)),
+				..migration.clone()
+			})
+			.collect();
+	}
+	// Replicas starting at once wait for each other on a Postgres advisory lock
+	migrator.set_locking(true);
+
+	let mut conn = pool.acquire().await.expect("Failed to connect to Postgres");
+	if schema == MIGRATIONS_SCHEMA {
+		conn.execute(r#"set search_path to "$user", public"#)
+			.await
+			.expect("Failed to set the search path");
+	} else {
+		conn.execute(format!("create schema if not exists {schema}").as_str())
+			.await
+			.expect("Failed to create the schema");
+	}
+	migrator
+		.run(&mut *conn)
+		.await
+		.expect("Failed to migrate the database");
+	conn.execute("reset search_path")
+		.await
+		.expect("Failed to reset the search path");
+	info!(schema, "Migrated");
+}
+
+/// Exchanges with shapes not assigned to a cluster yet.
+async fn unclustered_exchanges(conn: &PgPool) -> Result<Vec<(String, String)>, sqlx::Error> {
+	let exchanges =
+		sqlx::query!("select distinct vhost, exchange from entity where cluster_id is null")
+			.fetch_all(conn)
+			.await?;
+
+	Ok(exchanges
+		.into_iter()
+		.map(|ex| (ex.vhost, ex.exchange))
+		.collect())
+}
+
+/// Shapes on an exchange, most common first.
+async fn shapes(
+	conn: &PgPool,
+	vhost: &str,
+	exchange: &str,
+) -> Result<Vec<StoredShape>, sqlx::Error> {
+	let shapes = sqlx::query!(
+		r#"
+		select
+			key_digest::text as "key_digest!",
+			key_paths,
+			add_counts(sum(count), 0) as "count!"
+		from entity
+		where vhost = $1 and exchange = $2
+		group by key_digest, key_paths
+		order by 3 desc, 1
+	"#,
+		vhost,
+		exchange,
+	)
+	.fetch_all(conn)
+	.await?;
+
+	Ok(shapes
+		.into_iter()
+		.map(|s| StoredShape {
+			key_digest: s.key_digest,
+			key_paths: s.key_paths,
+			count: s.count,
+		})
+		.collect())
+}
+
+/// Replaces the clusters on an exchange.
+async fn replace_clusters(
+	conn: &PgPool,
+	vhost: &str,
+	exchange: &str,
+	clusters: &[Cluster],
+) -> Result<(), sqlx::Error> {
+	let mut key_digest = Vec::new();
+	let mut cluster_id = Vec::new();
+	let mut representative_digest = Vec::with_capacity(clusters.len());
+	let mut representative_key_paths = Vec::with_capacity(clusters.len());
+	let mut cluster_shapes = Vec::with_capacity(clusters.len());
+	let mut cluster_count = Vec::with_capacity(clusters.len());
+	for cluster in clusters {
+		for member in &cluster.members {
+			key_digest.push(member.clone());
+			cluster_id.push(cluster.id.clone());
+		}
+		representative_digest.push(cluster.id.clone());
+		representative_key_paths.push(text_array(&cluster.key_paths));
+		cluster_shapes.push(cluster.shapes);
+		cluster_count.push(cluster.count);
+	}
+
+	let mut tx = conn.begin().await?;
+	sqlx::query!(
+		r#"
+		update entity as e
+		set cluster_id = c.cluster_id
+		from (
+			select
+				unnest($3::uuid[]) as key_digest,
+				unnest($4::uuid[]) as cluster_id
+		) as c
+		where
+			e.vhost = $1
+			and e.exchange = $2
+			and e.key_digest = c.key_digest
+			and e.cluster_id is distinct from c.cluster_id
+	"#,
+		vhost,
+		exchange,
+		&key_digest as &[String],
+		&cluster_id as &[String],
+	)
+	.execute(&mut *tx)
+	.await?;
+	sqlx::query!(
+		"delete from entity_cluster where vhost = $1 and exchange = $2",
+		vhost,
+		exchange,
+	)
+	.execute(&mut *tx)
+	.await?;
+	sqlx::query!(
+		r#"
+		insert into entity_cluster (vhost, exchange, cluster_id, key_paths, shapes, count)
+		select $1, $2, cluster_id, key_paths::text[], shapes, count
+		from (
+			select
+				unnest($3::uuid[]) as cluster_id,
+				unnest($4::text[]) as key_paths,
+				unnest($5::integer[]) as shapes,
+				unnest($6::bigint[]) as count
+		) as new
+	"#,
+		vhost,
+		exchange,
+		&representative_digest as &[String],
+		&representative_key_paths[..],
+		&cluster_shapes[..],
+		&cluster_count[..],
+	)
+	.execute(&mut *tx)
+	.await?;
+	tx.commit().await
+}
+
+/// Merges time buckets older than `after` hours into `bucket` wide ones.
+async fn downsample(conn: &PgPool, after: u32, bucket: &str) -> Result<u64, sqlx::Error> {
+	let result = sqlx::query!(
+		r#"
+		with old as (
+			delete from entity_counts
+			where
+				bucket < now() - make_interval(hours => $1)
+				and bucket <> date_trunc($2, bucket)
+			returning *
+		)
+		insert into entity_counts as c (
+			id,
+			key_digest,
+			vhost,
+			exchange,
+			discriminator,
+			cloudevent_type,
+			cloudevent_source,
+			envelope_id,
+			bucket,
+			count
+		)
+		select
+			id,
+			key_digest,
+			vhost,
+			exchange,
+			discriminator,
+			cloudevent_type,
+			cloudevent_source,
+			envelope_id,
+			date_trunc($2, bucket),
+			add_counts(sum(count), 0)
+		from old
+		group by 1, 2, 3, 4, 5, 6, 7, 8, 9
+		on conflict
+			on constraint entity_counts_pkey
+				do update set count = add_counts(c.count, EXCLUDED.count)
+	"#,
+		i32::try_from(after).unwrap_or(i32::MAX),
+		bucket,
+	)
+	.execute(conn)
+	.await?;
+
+	Ok(result.rows_affected())
+}
+
+/// Exchanges with their own retention and their maximum age in hours.
+fn retention_rules(retention: &Retention) -> (Vec<String>, Vec<i32>) {
+	retention
+		.exchanges
+		.iter()
+		.map(|(exchange, hours)| (exchange.clone(), i32::try_from(*hours).unwrap_or(i32::MAX)))
+		.unzip()
+}
+
+async fn prune_shapes(
+	conn: &PgPool,
+	retention: &Retention,
+	limit: u32,
+) -> Result<u64, sqlx::Error> {
+	let ruled = retention.ruled_exchanges();
+	let mut deleted = 0;
+	for (exchange, hours) in retention.passes() {
+		let remaining = u64::from(limit) - deleted;
+		if remaining == 0 {
+			break;
+		}
+		let remaining = i64::try_from(remaining).unwrap_or(i64::MAX);
+		let hours = i32::try_from(hours).unwrap_or(i32::MAX);
+		let result = match exchange {
+			Some(exchange) => {
+				sqlx::query!(
+					r#"
+					delete from entity
+					where ctid = any(array(
+						select ctid
+						from entity
+						where
+							exchange = $1
+							and last_seen_at < now() - make_interval(hours => $2)
+						limit $3
+					))
+				"#,
+					exchange,
+					hours,
+					remaining,
+				)
+				.execute(conn)
+				.await?
+			}
+			None => {
+				sqlx::query!(
+					r#"
+					delete from entity
+					where ctid = any(array(
+						select ctid
+						from entity
+						where
+							exchange <> all($1)
+							and last_seen_at < now() - make_interval(hours => $2)
+						limit $3
+					))
+				"#,
+					&ruled,
+					hours,
+					remaining,
+				)
+				.execute(conn)
+				.await?
+			}
+		};
+		deleted += result.rows_affected();
+	}
+
+	Ok(deleted)
+}
+
+async fn prune_buckets(
+	conn: &PgPool,
+	retention: &Retention,
+	limit: u32,
+) -> Result<u64, sqlx::Error> {
+	let ruled = retention.ruled_exchanges();
+	let mut deleted = 0;
+	for (exchange, hours) in retention.passes() {
+		let remaining = u64::from(limit) - deleted;
+		if remaining == 0 {
+			break;
+		}
+		let remaining = i64::try_from(remaining).unwrap_or(i64::MAX);
+		let hours = i32::try_from(hours).unwrap_or(i32::MAX);
+		let result = match exchange {
+			Some(exchange) => {
+				sqlx::query!(
+					r#"
+					delete from entity_counts
+					where ctid = any(array(
+						select ctid
+						from entity_counts
+						where
+							exchange = $1
+							and bucket < now() - make_interval(hours => $2)
+						limit $3
+					))
+				"#,
+					exchange,
+					hours,
+					remaining,
+				)
+				.execute(conn)
+				.await?
+			}
+			None => {
+				sqlx::query!(
+					r#"
+					delete from entity_counts
+					where ctid = any(array(
+						select ctid
+						from entity_counts
+						where
+							exchange <> all($1)
+							and bucket < now() - make_interval(hours => $2)
+						limit $3
+					))
+				"#,
+					&ruled,
+					hours,
+					remaining,
+				)
+				.execute(conn)
+				.await?
+			}
+		};
+		deleted += result.rows_affected();
+	}
+
+	Ok(deleted)
+}
+
+async fn prune_clusters(conn: &PgPool) -> Result<u64, sqlx::Error> {
+	let result = sqlx::query!(
+		r#"
+		delete from entity_cluster c
+		where not exists (
+			select from entity e
+			where
+				e.vhost = c.vhost
+				and e.exchange = c.exchange
+				and e.cluster_id = c.cluster_id
+		)
+	"#
+	)
+	.execute(conn)
+	.await?;
+
+	Ok(result.rows_affected())
+}
+
+async fn prunable(conn: &PgPool, retention: &Retention) -> Result<Vec<Prunable>, sqlx::Error> {
+	let (exchanges, hours) = retention_rules(retention);
+	let rows = sqlx::query!(
+		r#"
+		with rules as (
+			select * from unnest($1::text[], $2::integer[]) as r(exchange, hours)
+		),
+		shapes as (
+			select e.vhost, e.exchange, count(*) as shapes
+			from entity e
+			left join rules r using (exchange)
+			where
+				coalesce(r.hours, $3) > 0
+				and e.last_seen_at < now() - make_interval(hours => coalesce(r.hours, $3))
+			group by 1, 2
+		),
+		buckets as (
+			select c.vhost, c.exchange, count(*) as buckets
+			from entity_counts c
+			left join rules r using (exchange)
+			where
+				coalesce(r.hours, $3) > 0
+				and c.bucket < now() - make_interval(hours => coalesce(r.hours, $3))
+			group by 1, 2
+		)
+		select
+			vhost as "vhost!",
+			exchange as "exchange!",
+			coalesce(shapes, 0) as "shapes!",
+			coalesce(buckets, 0) as "buckets!"
+		from shapes
+		full join buckets using (vhost, exchange)
+		order by 1, 2
+	"#,
+		&exchanges,
+		&hours,
+		i32::try_from(retention.default).unwrap_or(i32::MAX),
+	)
+	.fetch_all(conn)
+	.await?;
+
+	Ok(rows
+		.into_iter()
+		.map(|row| Prunable {
+			vhost: row.vhost,
+			exchange: row.exchange,
+			shapes: row.shapes,
+			buckets: row.buckets,
+		})
+		.collect())
+}
+
+impl Storage for PgPool {
+	async fn insert_counts(
+		&self,
+		counts: &HashMap<Payload, Counts>,
+		options: &InsertOptions,
+	) -> Result<(), sqlx::Error> {
+		insert_counts(
+			self,
+			counts,
+			options.bucket.as_deref(),
+			options.copy,
+			options.notify_channel.as_deref(),
+		)
+		.await
+	}
+
+	async fn unclustered_exchanges(&self) -> Result<Vec<(String, String)>, sqlx::Error> {
+		unclustered_exchanges(self).await
+	}
+
+	async fn shapes(&self, vhost: &str, exchange: &str) -> Result<Vec<StoredShape>, sqlx::Error> {
+		shapes(self, vhost, exchange).await
+	}
+
+	async fn replace_clusters(
+		&self,
+		vhost: &str,
+		exchange: &str,
+		clusters: &[Cluster],
+	) -> Result<(), sqlx::Error> {
+		replace_clusters(self, vhost, exchange, clusters).await
+	}
+
+	async fn prune_shapes(&self, retention: &Retention, limit: u32) -> Result<u64, sqlx::Error> {
+		prune_shapes(self, retention, limit).await
+	}
+
+	async fn prune_buckets(&self, retention: &Retention, limit: u32) -> Result<u64, sqlx::Error> {
+		prune_buckets(self, retention, limit).await
+	}
+
+	async fn prune_clusters(&self) -> Result<u64, sqlx::Error> {
+		prune_clusters(self).await
+	}
+
+	async fn prunable(&self, retention: &Retention) -> Result<Vec<Prunable>, sqlx::Error> {
+		prunable(self, retention).await
+	}
+
+	async fn downsample(&self, after: u32, bucket: &str) -> Result<u64, sqlx::Error> {
+		downsample(self, after, bucket).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+
+	use sqlx::Row;
+
+	use super::*;
+	use crate::config;
+	use crate::db::key_digest;
+	use crate::payload::Producer;
+
+	const EXCHANGE: &str = "robserver.test";
+
+	#[test]
+	fn schema_rewrite() {
+		assert_eq!(
+			in_schema(
+				"create schema data;\ncreate table data.entity (metadata.x int, data jsonb);",
+				"robserver_staging"
+			),
+			"create schema if not exists robserver_staging;\n\
+			create table robserver_staging.entity (metadata.x int, data jsonb);"
+		);
+		assert_eq!(
+			in_schema("select data.key_paths(data.x)", "s"),
+			"select s.key_paths(s.x)"
+		);
+		assert_eq!(
+			in_schema(
+				"select 'data.x', \"data.y\" from data.z -- data.w\n/* data.v */ where $$ data.u $$",
+				"s"
+			),
+			"select 'data.x', \"data.y\" from s.z -- data.w\n/* data.v */ where $$ s.u $$"
+		);
+	}
+
+	#[test]
+	fn schema_rewrite_migrations() {
+		const SCHEMA: &str = "robserver_staging";
+
+		let migrator = sqlx::migrate!();
+		let created = format!("create schema if not exists {SCHEMA};");
+		assert!(migrator
+			.migrations
+			.iter()
+			.any(|migration| in_schema(&migration.sql, SCHEMA).contains(&created)));
+
+		for migration in migrator.migrations.iter() {
+			let rewritten = in_schema(&migration.sql, SCHEMA);
+			// No qualified reference is left to rewrite
+			assert_eq!(
+				in_schema(&rewritten, "elsewhere"),
+				rewritten,
+				"{}",
+				migration.description
+			);
+			// Only the schema changed
+			assert_eq!(
+				rewritten
+					.replace(&created, "create schema data;")
+					.replace(&format!("{SCHEMA}."), "data."),
+				migration.sql,
+				"{}",
+				migration.description
+			);
+			assert!(!rewritten.contains("create schema data"));
+			for comment in migration.sql.lines().filter(|line| line.starts_with("--")) {
+				assert!(rewritten.contains(comment), "{comment}");
+			}
+		}
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`.
+	#[tokio::test]
+	#[ignore]
+	async fn count_near_limit() {
+		let pool = connect(&config::psql::get_url()).await;
+		let clean = || async {
+			sqlx::query("delete from entity where exchange = $1")
+				.bind(EXCHANGE)
+				.execute(&pool)
+				.await
+				.unwrap();
+		};
+		clean().await;
+
+		let payload = Payload::new(
+			br#"{"near":"limit"}"#.to_vec(),
+			String::from("/"),
+			String::from(EXCHANGE),
+			String::new(),
+		);
+		let counts = HashMap::from([(payload, Counts::from(10))]);
+		insert_counts(&pool, &counts, Some("hour"), false, None)
+			.await
+			.unwrap();
+		sqlx::query("update entity set count = $2 where exchange = $1")
+			.bind(EXCHANGE)
+			.bind(i64::MAX - 5)
+			.execute(&pool)
+			.await
+			.unwrap();
+		insert_counts(&pool, &counts, Some("hour"), false, None)
+			.await
+			.unwrap();
+
+		let count: i64 = sqlx::query_scalar("select count from entity where exchange = $1")
+			.bind(EXCHANGE)
+			.fetch_one(&pool)
+			.await
+			.unwrap();
+		assert_eq!(count, i64::MAX);
+		let bucketed: i64 =
+			sqlx::query_scalar("select count from entity_counts where exchange = $1")
+				.bind(EXCHANGE)
+				.fetch_one(&pool)
+				.await
+				.unwrap();
+		assert_eq!(bucketed, 20);
+
+		clean().await;
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`.
+	#[tokio::test]
+	#[ignore]
+	async fn producers_and_queues() {
+		let pool = connect(&config::psql::get_url()).await;
+		let clean = || async {
+			sqlx::query("delete from entity where exchange = $1")
+				.bind(EXCHANGE)
+				.execute(&pool)
+				.await
+				.unwrap();
+		};
+		clean().await;
+
+		let producer = |app_id: &str| Producer {
+			app_id: app_id.to_string(),
+			user_id: String::from("guest"),
+		};
+		let payload = Payload::new(
+			br#"{"produced":"by"}"#.to_vec(),
+			String::from("/"),
+			String::from(EXCHANGE),
+			String::new(),
+		);
+		let counts = HashMap::from([(
+			payload,
+			Counts {
+				count: 3,
+				producers: vec![(producer("billing"), 2), (producer("orders"), 1)],
+				queues: vec![(String::from("invoices"), 2)],
+			},
+		)]);
+		for copy in [false, true] {
+			insert_counts(&pool, &counts, None, copy, None)
+				.await
+				.unwrap();
+		}
+
+		let rows: Vec<(String, String, i64, bool)> = sqlx::query_as(
+			r#"
+			select app_id, user_id, count, first_seen_at < last_seen_at
+			from entity_producer
+			where exchange = $1
+			order by app_id
+		"#,
+		)
+		.bind(EXCHANGE)
+		.fetch_all(&pool)
+		.await
+		.unwrap();
+		assert_eq!(
+			rows,
+			vec![
+				(String::from("billing"), String::from("guest"), 4, true),
+				(String::from("orders"), String::from("guest"), 2, true)
+			]
+		);
+		let queues: Vec<(String, i64)> =
+			sqlx::query_as("select queue, count from entity_queue where exchange = $1")
+				.bind(EXCHANGE)
+				.fetch_all(&pool)
+				.await
+				.unwrap();
+		assert_eq!(queues, vec![(String::from("invoices"), 4)]);
+
+		clean().await;
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`.
+	#[tokio::test]
+	#[ignore]
+	async fn key_paths_with_separators() {
+		let pool = connect(&config::psql::get_url()).await;
+		let payload = Payload::new(
+			br#"{"a\nb":1,"c\"d\\e,{f}":2}"#.to_vec(),
+			String::from("/"),
+			String::from(EXCHANGE),
+			String::new(),
+		);
+		assert_eq!(payload.key_paths, vec!["a\nb", "c\"d\\e,{f}"]);
+		let counts = HashMap::from([(
+			payload.clone(),
+			Counts {
+				count: 1,
+				producers: vec![(Producer::default(), 1)],
+				queues: Vec::new(),
+			},
+		)]);
+		for copy in [false, true] {
+			sqlx::query("delete from entity where exchange = $1")
+				.bind(EXCHANGE)
+				.execute(&pool)
+				.await
+				.unwrap();
+			insert_counts(&pool, &counts, Some("hour"), copy, None)
+				.await
+				.unwrap();
+
+			let rows: Vec<(Vec<String>, String)> = sqlx::query_as(
+				r#"
+				select e.key_paths, e.key_digest::text
+				from entity e
+				join entity_producer p using (id, key_digest, vhost, exchange)
+				join entity_counts c using (id, key_digest, vhost, exchange)
+				where e.exchange = $1
+			"#,
+			)
+			.bind(EXCHANGE)
+			.fetch_all(&pool)
+			.await
+			.unwrap();
+			assert_eq!(
+				rows,
+				vec![(payload.key_paths.clone(), key_digest(&payload.key_paths))]
+			);
+		}
+
+		sqlx::query("delete from entity where exchange = $1")
+			.bind(EXCHANGE)
+			.execute(&pool)
+			.await
+			.unwrap();
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`.
+	#[tokio::test]
+	#[ignore]
+	async fn prune_by_index() {
+		let pool = connect(&config::psql::get_url()).await;
+		let mut tx = pool.begin().await.unwrap();
+		// Whether the indexes can be used at all, regardless of the table size
+		sqlx::query("set local enable_seqscan = off")
+			.execute(&mut *tx)
+			.await
+			.unwrap();
+
+		for (sql, index) in [
+			(
+				"select ctid from entity where exchange = $1 and last_seen_at < now() - make_interval(hours => $2) limit $3",
+				"entity_exchange_last_seen_at_idx",
+			),
+			(
+				"select ctid from entity where exchange <> all(array[$1]) and last_seen_at < now() - make_interval(hours => $2) limit $3",
+				"entity_last_seen_at_idx",
+			),
+			(
+				"select ctid from entity_counts where exchange = $1 and bucket < now() - make_interval(hours => $2) limit $3",
+				"entity_counts_exchange_bucket_idx",
+			),
+			(
+				"select ctid from entity_counts where exchange <> all(array[$1]) and bucket < now() - make_interval(hours => $2) limit $3",
+				"entity_counts_bucket_idx",
+			),
+		] {
+			let plan: Vec<String> = sqlx::query_scalar(&format!("explain {sql}"))
+				.bind(EXCHANGE)
+				.bind(24)
+				.bind(1000i64)
+				.fetch_all(&mut *tx)
+				.await
+				.unwrap();
+			let plan = plan.join("\n");
+			// The cutoff bounds the index scan rather than filtering every row
+			assert!(plan.contains(&format!(" {index} ")), "{plan}");
+			let cond = plan
+				.lines()
+				.find(|line| line.trim_start().starts_with("Index Cond:"))
+				.unwrap_or_default();
+			assert!(
+				cond.contains("last_seen_at <") || cond.contains("bucket <"),
+				"{plan}"
+			);
+		}
+
+		let pruned = prune_shapes(&pool, &"1,other:2".parse().unwrap(), 10)
+			.await
+			.unwrap();
+		assert!(pruned <= 10);
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`.
+	#[tokio::test]
+	#[ignore]
+	async fn notify_created() {
+		const CHANNEL: &str = "robserver_test";
+		let pool = connect(&config::psql::get_url()).await;
+		sqlx::query("delete from entity where exchange = $1")
+			.bind(EXCHANGE)
+			.execute(&pool)
+			.await
+			.unwrap();
+		let mut listener = sqlx::postgres::PgListener::connect_with(&pool)
+			.await
+			.unwrap();
+		listener.listen(CHANNEL).await.unwrap();
+
+		let mut payload = Payload::new(
+			br#"{"notify":"me"}"#.to_vec(),
+			String::from("/"),
+			String::from(EXCHANGE),
+			String::new(),
+		);
+		payload.routing_key = String::from("some.key");
+		let counts = HashMap::from([(payload.clone(), Counts::from(1))]);
+		insert_counts(&pool, &counts, None, false, Some(CHANNEL))
+			.await
+			.unwrap();
+		insert_counts(&pool, &counts, None, false, Some(CHANNEL))
+			.await
+			.unwrap();
+
+		let notification: Value =
+			serde_json::from_str(listener.recv().await.unwrap().payload()).unwrap();
+		assert_eq!(notification["vhost"], "/");
+		assert_eq!(notification["exchange"], EXCHANGE);
+		assert_eq!(notification["routing_key"], "some.key");
+		assert_eq!(notification["id"].to_string(), payload.id.to_string());
+		// Only the first insert is a new shape
+		let again =
+			tokio::time::timeout(std::time::Duration::from_millis(500), listener.recv()).await;
+		assert!(again.is_err());
+
+		sqlx::query("delete from entity where exchange = $1")
+			.bind(EXCHANGE)
+			.execute(&pool)
+			.await
+			.unwrap();
+	}
+
+	fn snapshot_query() -> &'static str {
+		r#"
+		select
+			id::text, payload::text, raw_payload, routing_key, count, truncated_count,
+			ignore_rules::text, key_paths::text, key_digest::text, discriminator, cloudevent_type,
+			cloudevent_source, envelope_id::text, normalized_id::text, normalized_keys
+		from entity
+		where exchange = $1
+		order by id, key_digest
+	"#
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`.
+	#[tokio::test]
+	#[ignore]
+	async fn copy_matches_unnest() {
+		let pool = connect(&config::psql::get_url()).await;
+		let payloads = [
+			&br#"{"a":1,"b":{"c":[1,2]}}"#[..],
+			br#"{"a":"multi\nline"}"#,
+			b"not json",
+		];
+		let mut snapshots = Vec::new();
+		for copy in [false, true] {
+			sqlx::query("delete from entity where exchange = $1")
+				.bind(EXCHANGE)
+				.execute(&pool)
+				.await
+				.unwrap();
+			let counts: HashMap<Payload, Counts> = payloads
+				.iter()
+				.enumerate()
+				.map(|(i, data)| {
+					let payload = Payload::new(
+						data.to_vec(),
+						String::from("/"),
+						String::from(EXCHANGE),
+						String::from("some.key"),
+					);
+					(payload, Counts::from(i + 1))
+				})
+				.collect();
+			insert_counts(&pool, &counts, Some("hour"), copy, None)
+				.await
+				.unwrap();
+			insert_counts(&pool, &counts, Some("hour"), copy, None)
+				.await
+				.unwrap();
+
+			let rows = sqlx::query(snapshot_query())
+				.bind(EXCHANGE)
+				.fetch_all(&pool)
+				.await
+				.unwrap();
+			let snapshot: Vec<Vec<Option<String>>> = rows
+				.iter()
+				.map(|row| {
+					(0..row.len())
+						.map(|i| {
+							row.try_get::<Option<String>, _>(i)
+								.or_else(|_| {
+									row.try_get::<Option<i64>, _>(i)
+										.map(|v| v.map(|v| v.to_string()))
+								})
+								.or_else(|_| {
+									row.try_get::<Option<bool>, _>(i)
+										.map(|v| v.map(|v| v.to_string()))
+								})
+								.unwrap()
+						})
+						.collect()
+				})
+				.collect();
+			snapshots.push(snapshot);
+		}
+
+		assert_eq!(snapshots[0].len(), payloads.len());
+		assert_eq!(snapshots[0], snapshots[1]);
+		sqlx::query("delete from entity where exchange = $1")
+			.bind(EXCHANGE)
+			.execute(&pool)
+			.await
+			.unwrap();
+	}
+
+	/// Needs a migrated database at `ROBSERVER_PG_ADDR`. Compares the insert methods on large
+	/// batches with large payloads, run with `--nocapture` to see the timings.
+	#[ignore = "ignore benchmarks for faster test runs"]
+	#[tokio::test]
+	async fn bench_insert_counts() {
+		let pool = connect(&config::psql::get_url()).await;
+		let padding = "x".repeat(2_000);
+		let counts: HashMap<Payload, Counts> = (0..50_000)
+			.map(|i| {
+				let data = format!(r#"{{"key_{i}":1,"padding":"{padding}"}}"#);
+				let payload = Payload::new(
+					data.into_bytes(),
+					String::from("/"),
+					String::from(EXCHANGE),
+					String::new(),
+				);
+				(payload, Counts::from(1))
+			})
+			.collect();
+
+		for copy in [false, true] {
+			sqlx::query("delete from entity where exchange = $1")
+				.bind(EXCHANGE)
+				.execute(&pool)
+				.await
+				.unwrap();
+			let start = Instant::now();
+			insert_counts(&pool, &counts, Some("hour"), copy, None)
+				.await
+				.unwrap();
+			let inserted = start.elapsed();
+			let start = Instant::now();
+			insert_counts(&pool, &counts, Some("hour"), copy, None)
+				.await
+				.unwrap();
+			let updated = start.elapsed();
+			println!("copy: {copy}, insert: {inserted:?}, update: {updated:?}");
+
+			let total: i64 =
+				sqlx::query_scalar("select sum(count)::bigint from entity where exchange = $1")
+					.bind(EXCHANGE)
+					.fetch_one(&pool)
+					.await
+					.unwrap();
+			assert_eq!(total, 100_000);
+		}
+
+		sqlx::query("delete from entity where exchange = $1")
+			.bind(EXCHANGE)
+			.execute(&pool)
+			.await
+			.unwrap();
+	}
+}
@@ -0,0 +1,106 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use sqlx::sqlite::SqliteError;
+use tokio::time::Duration;
+
+/// SQLSTATE classes and codes worth retrying: connection exceptions, insufficient resources,
+/// operator intervention (e.g. shutdowns during a failover), serialization failures and deadlocks.
+const TRANSIENT_STATES: [&str; 6] = ["08", "53", "57P", "40001", "40P01", "55P03"];
+/// SQLite primary result codes worth retrying: `SQLITE_BUSY` and `SQLITE_LOCKED`.
+const TRANSIENT_SQLITE_CODES: [i32; 2] = [5, 6];
+
+/// Whether retrying a failed query can succeed without changing it.
+pub fn is_transient(error: &sqlx::Error) -> bool {
+	match error {
+		sqlx::Error::Io(_)
+		| sqlx::Error::Tls(_)
+		| sqlx::Error::Protocol(_)
+		| sqlx::Error::PoolTimedOut
+		| sqlx::Error::WorkerCrashed => true,
+		sqlx::Error::Database(error) if error.try_downcast_ref::<SqliteError>().is_some() => error
+			.code()
+			.and_then(|code| code.parse::<i32>().ok())
+			.is_some_and(|code| TRANSIENT_SQLITE_CODES.contains(&(code & 0xFF))),
+		sqlx::Error::Database(error) => error
+			.code()
+			.is_some_and(|code| TRANSIENT_STATES.iter().any(|state| code.starts_with(state))),
+		_ => false,
+	}
+}
+
+/// Exponential backoff with jitter: every delay is between half and all of the exponentially
+/// growing one, capped to `max`.
+pub struct Backoff {
+	min: Duration,
+	max: Duration,
+	attempts: u32,
+	random: RandomState,
+}
+
+impl Backoff {
+	pub fn new(min: Duration, max: Duration) -> Backoff {
+		Backoff {
+			min,
+			max: max.max(min),
+			attempts: 0,
+			random: RandomState::new(),
+		}
+	}
+
+	pub fn attempts(&self) -> u32 {
+		self.attempts
+	}
+
+	pub fn reset(&mut self) {
+		self.attempts = 0;
+	}
+
+	pub fn next_delay(&mut self) -> Duration {
+		let delay = self
+			.min
+			.saturating_mul(2u32.saturating_pow(self.attempts))
+			.min(self.max);
+		self.attempts = self.attempts.saturating_add(1);
+
+		let mut hasher = self.random.build_hasher();
+		hasher.write_u32(self.attempts);
+		let jitter = (hasher.finish() % 1_000) as f64 / 1_000.0;
+		delay / 2 + (delay / 2).mul_f64(jitter)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn classification() {
+		assert!(is_transient(&sqlx::Error::PoolTimedOut));
+		assert!(is_transient(&sqlx::Error::Io(std::io::Error::from(
+			std::io::ErrorKind::ConnectionReset
+		))));
+		assert!(!is_transient(&sqlx::Error::RowNotFound));
+		assert!(!is_transient(&sqlx::Error::PoolClosed));
+	}
+
+	#[test]
+	fn backoff() {
+		let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+		let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+
+		for (delay, max) in delays.iter().zip([100, 200, 400, 800, 1_000, 1_000]) {
+			assert!(*delay >= Duration::from_millis(max / 2), "{delay:?}");
+			assert!(*delay <= Duration::from_millis(max), "{delay:?}");
+		}
+		assert_eq!(backoff.attempts(), 6);
+
+		backoff.reset();
+		assert!(backoff.next_delay() <= Duration::from_millis(100));
+
+		let mut backoff = Backoff::new(Duration::from_millis(100), Duration::MAX);
+		for _ in 0..100 {
+			assert!(backoff.next_delay() >= Duration::from_millis(50));
+		}
+	}
+}
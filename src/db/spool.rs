@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::{error, info, warn};
+
+const EXTENSION: &str = "spool";
+/// Length and checksum of the record, both little-endian.
+const HEADER_SIZE: u64 = 8;
+
+const CRC_TABLE: [u32; 256] = {
+	let mut table = [0; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 == 1 {
+				0xEDB8_8320 ^ (crc >> 1)
+			} else {
+				crc >> 1
+			};
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+};
+
+/// CRC-32 (IEEE) checksum.
+fn crc32(data: &[u8]) -> u32 {
+	!data.iter().fold(!0, |crc, b| {
+		CRC_TABLE[((crc ^ u32::from(*b)) & 0xFF) as usize] ^ (crc >> 8)
+	})
+}
+
+struct Segment {
+	seq: u64,
+	size: u64,
+}
+
+/// Append-only queue of records on disk, split into segment files that are deleted once read.
+///
+/// Every record is checksummed. A record failing the check, e.g. one partially written when the
+/// process stopped, is discarded along with the rest of its segment.
+pub struct Spool {
+	dir: PathBuf,
+	max_size: u64,
+	segment_size: u64,
+	segments: VecDeque<Segment>,
+	writer: Option<File>,
+	/// Position of the next record to read in the oldest segment.
+	read_offset: u64,
+	/// Position after the record last peeked at.
+	peeked_offset: Option<u64>,
+}
+
+impl Spool {
+	/// Opens the spool in `dir`, picking up segments left by a previous run.
+	pub fn open(dir: &Path, max_size: u64, segment_size: u64) -> io::Result<Spool> {
+		fs::create_dir_all(dir)?;
+		let mut segments = Vec::new();
+		for entry in fs::read_dir(dir)? {
+			let path = entry?.path();
+			if path.extension().is_some_and(|ext| ext == EXTENSION) {
+				if let Some(seq) = path
+					.file_stem()
+					.and_then(|stem| stem.to_str())
+					.and_then(|stem| stem.parse::<u64>().ok())
+				{
+					segments.push(Segment {
+						seq,
+						size: fs::metadata(&path)?.len(),
+					});
+				}
+			}
+		}
+		segments.sort_by_key(|segment| segment.seq);
+
+		let spool = Spool {
+			dir: dir.to_path_buf(),
+			max_size,
+			segment_size,
+			segments: segments.into(),
+			writer: None,
+			read_offset: 0,
+			peeked_offset: None,
+		};
+		if !spool.is_empty() {
+			info!(
+				segments = spool.segments.len(),
+				bytes = spool.size(),
+				"Found spooled counts"
+			);
+		}
+
+		Ok(spool)
+	}
+
+	fn path(&self, seq: u64) -> PathBuf {
+		self.dir.join(format!("{seq:020}.{EXTENSION}"))
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.segments.is_empty()
+	}
+
+	/// Bytes on disk, including records already read from the oldest segment.
+	pub fn size(&self) -> u64 {
+		self.segments.iter().map(|segment| segment.size).sum()
+	}
+
+	/// Appends a record and syncs it to disk. Returns `false` without writing anything if the
+	/// record doesn't fit into the maximum size.
+	pub fn append(&mut self, record: &[u8]) -> io::Result<bool> {
+		let len = HEADER_SIZE + record.len() as u64;
+		if self.size() + len > self.max_size {
+			return Ok(false);
+		}
+
+		let full =
+			!matches!(self.segments.back(), Some(segment) if segment.size < self.segment_size);
+		// Also a new segment after reopening or a failed write, as the last one may end in a torn
+		// record that would discard anything appended after it
+		if full || self.writer.is_none() {
+			let seq = self.segments.back().map_or(0, |segment| segment.seq + 1);
+			let file = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(self.path(seq))?;
+			self.segments.push_back(Segment { seq, size: 0 });
+			self.writer = Some(file);
+		}
+
+		let mut buf = Vec::with_capacity(len as usize);
+		buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&crc32(record).to_le_bytes());
+		buf.extend_from_slice(record);
+		let writer = self.writer.as_mut().expect("segment open for writing");
+		if let Err(error) = writer.write_all(&buf).and_then(|()| writer.sync_data()) {
+			self.writer = None;
+			return Err(error);
+		}
+		if let Some(segment) = self.segments.back_mut() {
+			segment.size += len;
+		}
+
+		Ok(true)
+	}
+
+	/// The oldest record not consumed yet.
+	pub fn peek(&mut self) -> io::Result<Option<Vec<u8>>> {
+		while let Some(segment) = self.segments.front() {
+			let path = self.path(segment.seq);
+			match Self::read_record(&path, self.read_offset) {
+				Ok(Some(record)) => {
+					self.peeked_offset = Some(self.read_offset + HEADER_SIZE + record.len() as u64);
+					return Ok(Some(record));
+				}
+				Ok(None) => {}
+				Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+					error!(
+						?path,
+						bytes = segment.size.saturating_sub(self.read_offset),
+						%error,
+						"Discarding corrupted spooled counts"
+					);
+				}
+				Err(error) => return Err(error),
+			}
+			self.remove_oldest()?;
+		}
+
+		Ok(None)
+	}
+
+	/// Marks the record last peeked at as read.
+	pub fn consume(&mut self) -> io::Result<()> {
+		if let Some(offset) = self.peeked_offset.take() {
+			self.read_offset = offset;
+			let exhausted = self.segments.len() > 1
+				&& self
+					.segments
+					.front()
+					.is_some_and(|segment| offset >= segment.size);
+			if exhausted {
+				self.remove_oldest()?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn remove_oldest(&mut self) -> io::Result<()> {
+		if let Some(segment) = self.segments.pop_front() {
+			if self.segments.is_empty() {
+				self.writer = None;
+			}
+			fs::remove_file(self.path(segment.seq))?;
+		}
+		self.read_offset = 0;
+		self.peeked_offset = None;
+
+		Ok(())
+	}
+
+	fn read_record(path: &Path, offset: u64) -> io::Result<Option<Vec<u8>>> {
+		let mut file = File::open(path)?;
+		file.seek(SeekFrom::Start(offset))?;
+		let mut header = [0; HEADER_SIZE as usize];
+		match file.read_exact(&mut header) {
+			Ok(()) => {}
+			Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+				let remaining = file.metadata()?.len().saturating_sub(offset);
+				if remaining > 0 {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"truncated header",
+					));
+				}
+				return Ok(None);
+			}
+			Err(error) => return Err(error),
+		}
+
+		let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+		let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+		let mut record = Vec::new();
+		file.take(u64::from(len)).read_to_end(&mut record)?;
+		if record.len() != len as usize || crc32(&record) != checksum {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"checksum mismatch",
+			));
+		}
+
+		Ok(Some(record))
+	}
+}
+
+impl Drop for Spool {
+	fn drop(&mut self) {
+		if !self.is_empty() {
+			warn!(bytes = self.size(), "Leaving spooled counts behind");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("robserver-{name}-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		dir
+	}
+
+	fn drain(spool: &mut Spool) -> Vec<Vec<u8>> {
+		let mut records = Vec::new();
+		while let Some(record) = spool.peek().unwrap() {
+			records.push(record);
+			spool.consume().unwrap();
+		}
+		records
+	}
+
+	#[test]
+	fn checksum() {
+		assert_eq!(crc32(b""), 0);
+		assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+	}
+
+	#[test]
+	fn in_order() {
+		let dir = dir("in-order");
+		let mut spool = Spool::open(&dir, 1_000, 20).unwrap();
+		assert!(spool.is_empty());
+
+		for record in ["first", "second", "third"] {
+			assert!(spool.append(record.as_bytes()).unwrap());
+		}
+		assert_eq!(spool.segments.len(), 2);
+		assert_eq!(spool.peek().unwrap(), Some(b"first".to_vec()));
+		// Not consumed, so peeked again
+		assert_eq!(spool.peek().unwrap(), Some(b"first".to_vec()));
+		spool.consume().unwrap();
+		assert_eq!(spool.segments.len(), 2);
+		spool.peek().unwrap();
+		spool.consume().unwrap();
+		assert_eq!(spool.segments.len(), 1);
+
+		assert!(spool.append(b"fourth").unwrap());
+		assert_eq!(
+			drain(&mut spool),
+			vec![b"third".to_vec(), b"fourth".to_vec()]
+		);
+		assert!(spool.is_empty());
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+		// Usable after being emptied
+		assert!(spool.append(b"fifth").unwrap());
+		assert_eq!(drain(&mut spool), vec![b"fifth".to_vec()]);
+	}
+
+	#[test]
+	fn reopen() {
+		let dir = dir("reopen");
+		let mut spool = Spool::open(&dir, 1_000, 20).unwrap();
+		for record in ["first", "second", "third"] {
+			spool.append(record.as_bytes()).unwrap();
+		}
+		drop(spool);
+
+		let mut spool = Spool::open(&dir, 1_000, 20).unwrap();
+		assert_eq!(spool.size(), 3 * HEADER_SIZE + 16);
+		spool.append(b"fourth").unwrap();
+		assert_eq!(
+			drain(&mut spool),
+			vec![
+				b"first".to_vec(),
+				b"second".to_vec(),
+				b"third".to_vec(),
+				b"fourth".to_vec()
+			]
+		);
+	}
+
+	#[test]
+	fn max_size() {
+		let dir = dir("max-size");
+		let mut spool = Spool::open(&dir, 2 * HEADER_SIZE + 10, 1_000).unwrap();
+
+		assert!(spool.append(b"first").unwrap());
+		assert!(!spool.append(b"second").unwrap());
+		assert!(spool.append(b"third").unwrap());
+		assert_eq!(
+			drain(&mut spool),
+			vec![b"first".to_vec(), b"third".to_vec()]
+		);
+	}
+
+	#[test]
+	fn corrupted() {
+		let dir = dir("corrupted");
+		let mut spool = Spool::open(&dir, 1_000, 20).unwrap();
+		for record in ["first", "second", "third"] {
+			spool.append(record.as_bytes()).unwrap();
+		}
+		drop(spool);
+
+		// Flip a byte of "second" and cut "third" short
+		let path = dir.join(format!("{:020}.{EXTENSION}", 0));
+		let mut data = fs::read(&path).unwrap();
+		let last = data.len() - 1;
+		data[last] ^= 0xFF;
+		fs::write(&path, data).unwrap();
+		let path = dir.join(format!("{:020}.{EXTENSION}", 1));
+		let data = fs::read(&path).unwrap();
+		fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+		// Appended after the torn record of the last segment
+		let mut spool = Spool::open(&dir, 1_000, 20).unwrap();
+		spool.append(b"fourth").unwrap();
+		spool.append(b"fifth").unwrap();
+		assert_eq!(
+			drain(&mut spool),
+			vec![b"first".to_vec(), b"fourth".to_vec(), b"fifth".to_vec()]
+		);
+		assert!(spool.is_empty());
+	}
+}
@@ -0,0 +1,862 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{Row, SqlitePool};
+use tracing::{info, warn};
+
+use super::{
+	key_digest, to_count, Cluster, Counts, InsertOptions, Prunable, Retention, Storage, StoredShape,
+};
+use crate::payload::{Data, Payload};
+
+/// Timestamp format of `created_at` and `last_seen_at`.
+const TIMESTAMP: &str = "%Y-%m-%dT%H:%M:%fZ";
+
+/// Adds counts, saturating at the maximum integer as SQLite would switch to floating point.
+const ADD_COUNT: &str = r#"
+	case
+		when count > 9223372036854775807 - excluded.count then 9223372036854775807
+		else count + excluded.count
+	end
+"#;
+
+/// Exchanges with their own retention as `rules(exchange, hours)`, bound to `?1` as JSON, with
+/// the default retention in `?2`.
+const RETENTION_RULES: &str = r#"
+	with rules(exchange, hours) as (
+		select json_extract(value, '$[0]'), json_extract(value, '$[1]') from json_each(?1)
+	)
+"#;
+
+/// Shapes older than retention allows, as `e`.
+const PRUNABLE_SHAPES: &str = r#"
+	from entity e
+	left join rules r using (exchange)
+	where
+		coalesce(r.hours, ?2) > 0
+		and e.last_seen_at
+			< strftime('%Y-%m-%dT%H:%M:%fZ', 'now', '-' || coalesce(r.hours, ?2) || ' hours')
+"#;
+
+/// Time buckets older than retention allows, as `c`.
+const PRUNABLE_BUCKETS: &str = r#"
+	from entity_counts c
+	left join rules r using (exchange)
+	where
+		coalesce(r.hours, ?2) > 0
+		and c.bucket
+			< strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-' || coalesce(r.hours, ?2) || ' hours')
+"#;
+
+/// Format of the start of a time bucket of `unit` width.
+fn bucket_format(unit: &str) -> &'static str {
+	match unit {
+		"minute" => "%Y-%m-%dT%H:%M:00Z",
+		"hour" => "%Y-%m-%dT%H:00:00Z",
+		_ => "%Y-%m-%dT00:00:00Z",
+	}
+}
+
+async fn insert_counts(
+	conn: &SqlitePool,
+	counts: &HashMap<Payload, Counts>,
+	bucket: Option<&str>,
+) -> Result<(), sqlx::Error> {
+	info!(len = counts.len(), "Inserting/updating counts");
+	let mut ids = Vec::with_capacity(counts.len());
+	let mut tx = conn.begin().await?;
+	for (p, counts) in counts {
+		let to_add = counts.count;
+		if to_add == 0 {
+			continue;
+		}
+		let id = p.id.to_string();
+		let key_digest = key_digest(&p.key_paths);
+		let (cloudevent_type, cloudevent_source, envelope_id) = match &p.envelope {
+			Some(envelope) => (
+				envelope.r#type.as_str(),
+				envelope.source.as_str(),
+				envelope.id.to_string(),
+			),
+			None => ("", "", String::from("0")),
+		};
+		let (json, raw) = match &p.content {
+			Data::Json(value) => (Some(value.to_string()), None),
+			Data::Raw(value) => (None, String::from_utf8(value.clone()).ok()),
+		};
+		let count = to_count(to_add);
+
+		sqlx::query(&format!(
+			r#"
+			insert into entity (
+				id,
+				key_digest,
+				vhost,
+				exchange,
+				discriminator,
+				cloudevent_type,
+				cloudevent_source,
+				envelope_id,
+				payload,
+				raw_payload,
+				routing_key,
+				count,
+				truncated_count,
+				ignore_rules,
+				key_paths,
+				normalized_id,
+				normalized_keys
+			)
+			values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+			on conflict do update set
+				count = {ADD_COUNT},
+				truncated_count = case
+					when truncated_count > 9223372036854775807 - excluded.truncated_count
+						then 9223372036854775807
+					else truncated_count + excluded.truncated_count
+				end,
+				last_seen_at = strftime('{TIMESTAMP}', 'now'),
+				ignore_rules = excluded.ignore_rules,
+				normalized_id = excluded.normalized_id,
+				normalized_keys = excluded.normalized_keys
+		"#
+		))
+		.bind(&id)
+		.bind(&key_digest)
+		.bind(&p.vhost)
+		.bind(&p.exchange)
+		.bind(&p.discriminator)
+		.bind(cloudevent_type)
+		.bind(cloudevent_source)
+		.bind(&envelope_id)
+		.bind(json)
+		.bind(raw)
+		.bind(&p.routing_key)
+		.bind(count)
+		.bind(if p.truncated { count } else { 0 })
+		.bind(serde_json::to_string(&p.ignore_rules).expect("Failed to serialize ignore rules"))
+		.bind(serde_json::to_string(&p.key_paths).expect("Failed to serialize key paths"))
+		.bind(p.normalized_id.to_string())
+		.bind(p.normalized_keys)
+		.execute(&mut *tx)
+		.await?;
+
+		for (producer, producer_count) in &counts.producers {
+			sqlx::query(&format!(
+				r#"
+				insert into entity_producer (
+					id,
+					key_digest,
+					vhost,
+					exchange,
+					discriminator,
+					cloudevent_type,
+					cloudevent_source,
+					envelope_id,
+					app_id,
+					user_id,
+					count
+				)
+				values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+				on conflict do update set
+					count = {ADD_COUNT},
+					last_seen_at = strftime('{TIMESTAMP}', 'now')
+			"#
+			))
+			.bind(&id)
+			.bind(&key_digest)
+			.bind(&p.vhost)
+			.bind(&p.exchange)
+			.bind(&p.discriminator)
+			.bind(cloudevent_type)
+			.bind(cloudevent_source)
+			.bind(&envelope_id)
+			.bind(&producer.app_id)
+			.bind(&producer.user_id)
+			.bind(to_count(*producer_count))
+			.execute(&mut *tx)
+			.await?;
+		}
+
+		for (queue, queue_count) in &counts.queues {
+			sqlx::query(&format!(
+				r#"
+				insert into entity_queue (
+					id,
+					key_digest,
+					vhost,
+					exchange,
+					discriminator,
+					cloudevent_type,
+					cloudevent_source,
+					envelope_id,
+					queue,
+					count
+				)
+				values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+				on conflict do update set
+					count = {ADD_COUNT},
+					last_seen_at = strftime('{TIMESTAMP}', 'now')
+			"#
+			))
+			.bind(&id)
+			.bind(&key_digest)
+			.bind(&p.vhost)
+			.bind(&p.exchange)
+			.bind(&p.discriminator)
+			.bind(cloudevent_type)
+			.bind(cloudevent_source)
+			.bind(&envelope_id)
+			.bind(queue)
+			.bind(to_count(*queue_count))
+			.execute(&mut *tx)
+			.await?;
+		}
+
+		if let Some(bucket) = bucket {
+			sqlx::query(&format!(
+				r#"
+				insert into entity_counts (
+					id,
+					key_digest,
+					vhost,
+					exchange,
+					discriminator,
+					cloudevent_type,
+					cloudevent_source,
+					envelope_id,
+					bucket,
+					count
+				)
+				values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, strftime(?9, 'now'), ?10)
+				on conflict do update set count = {ADD_COUNT}
+			"#
+			))
+			.bind(&id)
+			.bind(&key_digest)
+			.bind(&p.vhost)
+			.bind(&p.exchange)
+			.bind(&p.discriminator)
+			.bind(cloudevent_type)
+			.bind(cloudevent_source)
+			.bind(&envelope_id)
+			.bind(bucket_format(bucket))
+			.bind(count)
+			.execute(&mut *tx)
+			.await?;
+		}
+		ids.push(id);
+	}
+
+	// Flag shapes sharing an id with a different key set. Only newly detected ones are returned.
+	let collisions = sqlx::query(
+		r#"
+		update entity
+		set collision = 1
+		where
+			not collision
+			and (id, vhost, exchange) in (
+				select id, vhost, exchange
+				from entity
+				where id in (select value from json_each(?1))
+				group by id, vhost, exchange
+				having count(distinct key_digest) > 1
+			)
+		returning id, vhost, exchange, discriminator, key_paths
+	"#,
+	)
+	.bind(serde_json::to_string(&ids).expect("Failed to serialize ids"))
+	.fetch_all(&mut *tx)
+	.await?;
+	for row in collisions {
+		warn!(
+			id = row.get::<String, _>("id"),
+			vhost = row.get::<String, _>("vhost"),
+			exchange = row.get::<String, _>("exchange"),
+			discriminator = row.get::<String, _>("discriminator"),
+			key_paths = row.get::<String, _>("key_paths"),
+			"Shape id collision"
+		);
+	}
+
+	tx.commit().await
+}
+
+/// Opens the database, creating it if missing, and brings its schema up to date.
+pub async fn connect(url: &str) -> SqlitePool {
+	info!("Opening SQLite database...");
+	let options = SqliteConnectOptions::from_str(url)
+		.expect("Invalid SQLite URL")
+		.create_if_missing(true)
+		.journal_mode(SqliteJournalMode::Wal);
+	// SQLite has a single writer anyway and a single connection shares in-memory databases
+	let pool = SqlitePoolOptions::new()
+		.max_connections(1)
+		.connect_with(options)
+		.await
+		.expect("Failed to open SQLite database");
+	info!("Opened");
+
+	pool
+}
+
+/// Applies the migrations embedded from `migrations/sqlite/` that haven't been applied yet.
+pub async fn migrate(pool: &SqlitePool) {
+	info!("Migrating...");
+	sqlx::migrate!("./migrations/sqlite")
+		.run(pool)
+		.await
+		.expect("Failed to migrate SQLite database");
+	info!("Migrated");
+}
+
+async fn unclustered_exchanges(conn: &SqlitePool) -> Result<Vec<(String, String)>, sqlx::Error> {
+	sqlx::query_as("select distinct vhost, exchange from entity where cluster_id is null")
+		.fetch_all(conn)
+		.await
+}
+
+async fn shapes(
+	conn: &SqlitePool,
+	vhost: &str,
+	exchange: &str,
+) -> Result<Vec<StoredShape>, sqlx::Error> {
+	let shapes: Vec<(String, String, i64)> = sqlx::query_as(
+		r#"
+		select key_digest, key_paths, sum(count)
+		from entity
+		where vhost = ?1 and exchange = ?2
+		group by key_digest, key_paths
+		order by 3 desc, 1
+	"#,
+	)
+	.bind(vhost)
+	.bind(exchange)
+	.fetch_all(conn)
+	.await?;
+
+	shapes
+		.into_iter()
+		.map(|(key_digest, key_paths, count)| {
+			Ok(StoredShape {
+				key_digest,
+				key_paths: serde_json::from_str(&key_paths)
+					.map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+				count,
+			})
+		})
+		.collect()
+}
+
+async fn replace_clusters(
+	conn: &SqlitePool,
+	vhost: &str,
+	exchange: &str,
+	clusters: &[Cluster],
+) -> Result<(), sqlx::Error> {
+	let mut tx = conn.begin().await?;
+	for cluster in clusters {
+		for member in &cluster.members {
+			sqlx::query(
+				r#"
+				update entity
+				set cluster_id = ?4
+				where
+					vhost = ?1
+					and exchange = ?2
+					and key_digest = ?3
+					and cluster_id is not ?4
+			"#,
+			)
+			.bind(vhost)
+			.bind(exchange)
+			.bind(member)
+			.bind(&cluster.id)
+			.execute(&mut *tx)
+			.await?;
+		}
+	}
+	sqlx::query("delete from entity_cluster where vhost = ?1 and exchange = ?2")
+		.bind(vhost)
+		.bind(exchange)
+		.execute(&mut *tx)
+		.await?;
+	for cluster in clusters {
+		sqlx::query(
+			r#"
+			insert into entity_cluster (vhost, exchange, cluster_id, key_paths, shapes, count)
+			values (?1, ?2, ?3, ?4, ?5, ?6)
+		"#,
+		)
+		.bind(vhost)
+		.bind(exchange)
+		.bind(&cluster.id)
+		.bind(serde_json::to_string(&cluster.key_paths).expect("Failed to serialize key paths"))
+		.bind(cluster.shapes)
+		.bind(cluster.count)
+		.execute(&mut *tx)
+		.await?;
+	}
+	tx.commit().await
+}
+
+async fn downsample(conn: &SqlitePool, after: u32, bucket: &str) -> Result<u64, sqlx::Error> {
+	let mut tx = conn.begin().await?;
+	let cutoff: String = sqlx::query_scalar("select strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)")
+		.bind(format!("-{after} hours"))
+		.fetch_one(&mut *tx)
+		.await?;
+	let result = sqlx::query(&format!(
+		r#"
+		insert into entity_counts (
+			id,
+			key_digest,
+			vhost,
+			exchange,
+			discriminator,
+			cloudevent_type,
+			cloudevent_source,
+			envelope_id,
+			bucket,
+			count
+		)
+		select
+			id,
+			key_digest,
+			vhost,
+			exchange,
+			discriminator,
+			cloudevent_type,
+			cloudevent_source,
+			envelope_id,
+			strftime(?2, bucket),
+			sum(count)
+		from entity_counts
+		where bucket < ?1 and bucket <> strftime(?2, bucket)
+		group by 1, 2, 3, 4, 5, 6, 7, 8, 9
+		on conflict do update set count = {ADD_COUNT}
+	"#
+	))
+	.bind(&cutoff)
+	.bind(bucket_format(bucket))
+	.execute(&mut *tx)
+	.await?;
+	sqlx::query("delete from entity_counts where bucket < ?1 and bucket <> strftime(?2, bucket)")
+		.bind(&cutoff)
+		.bind(bucket_format(bucket))
+		.execute(&mut *tx)
+		.await?;
+	tx.commit().await?;
+
+	Ok(result.rows_affected())
+}
+
+fn retention_rules(retention: &Retention) -> String {
+	serde_json::to_string(&retention.exchanges).expect("Failed to serialize retention")
+}
+
+/// Deletes up to `limit` rows of `table` with `column` older than retention allows, a pass per
+/// rule.
+async fn prune_passes(
+	conn: &SqlitePool,
+	retention: &Retention,
+	limit: u32,
+	table: &str,
+	column: &str,
+	format: &str,
+) -> Result<u64, sqlx::Error> {
+	let ruled =
+		serde_json::to_string(&retention.ruled_exchanges()).expect("Failed to serialize retention");
+	let mut deleted = 0;
+	for (exchange, hours) in retention.passes() {
+		let remaining = u64::from(limit) - deleted;
+		if remaining == 0 {
+			break;
+		}
+		let (filter, exchange) = match exchange {
+			Some(exchange) => ("exchange = ?1", exchange),
+			None => (
+				"exchange not in (select value from json_each(?1))",
+				ruled.as_str(),
+			),
+		};
+		let result = sqlx::query(&format!(
+			r#"
+			delete from {table}
+			where rowid in (
+				select rowid
+				from {table}
+				where
+					{filter}
+					and {column} < strftime('{format}', 'now', '-' || ?2 || ' hours')
+				limit ?3
+			)
+		"#
+		))
+		.bind(exchange)
+		.bind(hours)
+		.bind(i64::try_from(remaining).unwrap_or(i64::MAX))
+		.execute(conn)
+		.await?;
+		deleted += result.rows_affected();
+	}
+
+	Ok(deleted)
+}
+
+async fn prune_shapes(
+	conn: &SqlitePool,
+	retention: &Retention,
+	limit: u32,
+) -> Result<u64, sqlx::Error> {
+	prune_passes(conn, retention, limit, "entity", "last_seen_at", TIMESTAMP).await
+}
+
+async fn prune_buckets(
+	conn: &SqlitePool,
+	retention: &Retention,
+	limit: u32,
+) -> Result<u64, sqlx::Error> {
+	prune_passes(
+		conn,
+		retention,
+		limit,
+		"entity_counts",
+		"bucket",
+		"%Y-%m-%dT%H:%M:%SZ",
+	)
+	.await
+}
+
+async fn prune_clusters(conn: &SqlitePool) -> Result<u64, sqlx::Error> {
+	let result = sqlx::query(
+		r#"
+		delete from entity_cluster
+		where not exists (
+			select 1 from entity e
+			where
+				e.vhost = entity_cluster.vhost
+				and e.exchange = entity_cluster.exchange
+				and e.cluster_id = entity_cluster.cluster_id
+		)
+	"#,
+	)
+	.execute(conn)
+	.await?;
+
+	Ok(result.rows_affected())
+}
+
+async fn prunable(conn: &SqlitePool, retention: &Retention) -> Result<Vec<Prunable>, sqlx::Error> {
+	let rows: Vec<(String, String, i64, i64)> = sqlx::query_as(&format!(
+		r#"
+		{RETENTION_RULES},
+		shapes as (select e.vhost, e.exchange, count(*) as n {PRUNABLE_SHAPES} group by 1, 2),
+		buckets as (select c.vhost, c.exchange, count(*) as n {PRUNABLE_BUCKETS} group by 1, 2),
+		exchanges as (select vhost, exchange from shapes union select vhost, exchange from buckets)
+		select x.vhost, x.exchange, coalesce(s.n, 0), coalesce(b.n, 0)
+		from exchanges x
+		left join shapes s using (vhost, exchange)
+		left join buckets b using (vhost, exchange)
+		order by 1, 2
+	"#
+	))
+	.bind(retention_rules(retention))
+	.bind(retention.default)
+	.fetch_all(conn)
+	.await?;
+
+	Ok(rows
+		.into_iter()
+		.map(|(vhost, exchange, shapes, buckets)| Prunable {
+			vhost,
+			exchange,
+			shapes,
+			buckets,
+		})
+		.collect())
+}
+
+impl Storage for SqlitePool {
+	async fn insert_counts(
+		&self,
+		counts: &HashMap<Payload, Counts>,
+		options: &InsertOptions,
+	) -> Result<(), sqlx::Error> {
+		insert_counts(self, counts, options.bucket.as_deref()).await
+	}
+
+	async fn unclustered_exchanges(&self) -> Result<Vec<(String, String)>, sqlx::Error> {
+		unclustered_exchanges(self).await
+	}
+
+	async fn shapes(&self, vhost: &str, exchange: &str) -> Result<Vec<StoredShape>, sqlx::Error> {
+		shapes(self, vhost, exchange).await
+	}
+
+	async fn replace_clusters(
+		&self,
+		vhost: &str,
+		exchange: &str,
+		clusters: &[Cluster],
+	) -> Result<(), sqlx::Error> {
+		replace_clusters(self, vhost, exchange, clusters).await
+	}
+
+	async fn prune_shapes(&self, retention: &Retention, limit: u32) -> Result<u64, sqlx::Error> {
+		prune_shapes(self, retention, limit).await
+	}
+
+	async fn prune_buckets(&self, retention: &Retention, limit: u32) -> Result<u64, sqlx::Error> {
+		prune_buckets(self, retention, limit).await
+	}
+
+	async fn prune_clusters(&self) -> Result<u64, sqlx::Error> {
+		prune_clusters(self).await
+	}
+
+	async fn prunable(&self, retention: &Retention) -> Result<Vec<Prunable>, sqlx::Error> {
+		prunable(self, retention).await
+	}
+
+	async fn downsample(&self, after: u32, bucket: &str) -> Result<u64, sqlx::Error> {
+		downsample(self, after, bucket).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::payload::Producer;
+
+	const EXCHANGE: &str = "robserver.test";
+
+	fn payload(data: &str) -> Payload {
+		Payload::new(
+			data.as_bytes().to_vec(),
+			String::from("/"),
+			String::from(EXCHANGE),
+			String::new(),
+		)
+	}
+
+	#[test]
+	fn digest() {
+		// select md5(E'a\nb')::uuid
+		assert_eq!(
+			key_digest(&[String::from("a"), String::from("b")]),
+			"8cdeb444-17f3-c268-2659-5d5820cf5700"
+		);
+	}
+
+	#[tokio::test]
+	async fn counts() {
+		let pool = connect("sqlite::memory:").await;
+		migrate(&pool).await;
+		let counts = HashMap::from([
+			(payload(r#"{"a":1}"#), Counts::from(2)),
+			(payload(r#"{"b":1}"#), Counts::from(1)),
+		]);
+		insert_counts(&pool, &counts, Some("hour")).await.unwrap();
+		insert_counts(&pool, &counts, Some("hour")).await.unwrap();
+		sqlx::query("update entity set count = ?1 where key_paths = '[\"b\"]'")
+			.bind(i64::MAX - 1)
+			.execute(&pool)
+			.await
+			.unwrap();
+		insert_counts(&pool, &counts, None).await.unwrap();
+
+		let rows: Vec<(String, i64)> =
+			sqlx::query_as("select key_paths, count from entity order by key_paths")
+				.fetch_all(&pool)
+				.await
+				.unwrap();
+		assert_eq!(
+			rows,
+			vec![
+				(String::from(r#"["a"]"#), 6),
+				(String::from(r#"["b"]"#), i64::MAX)
+			]
+		);
+		let bucketed: i64 = sqlx::query_scalar("select sum(count) from entity_counts")
+			.fetch_one(&pool)
+			.await
+			.unwrap();
+		assert_eq!(bucketed, 6);
+
+		let shapes = pool.shapes("/", EXCHANGE).await.unwrap();
+		assert_eq!(shapes[0].key_paths, vec!["b"]);
+		assert_eq!(pool.unclustered_exchanges().await.unwrap().len(), 1);
+		let cluster = Cluster {
+			id: shapes[0].key_digest.clone(),
+			key_paths: shapes[0].key_paths.clone(),
+			shapes: 2,
+			count: i64::MAX,
+			members: shapes.iter().map(|s| s.key_digest.clone()).collect(),
+		};
+		pool.replace_clusters("/", EXCHANGE, &[cluster])
+			.await
+			.unwrap();
+		assert!(pool.unclustered_exchanges().await.unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn producers_and_queues() {
+		let pool = connect("sqlite::memory:").await;
+		migrate(&pool).await;
+		let producer = |app_id: &str| Producer {
+			app_id: app_id.to_string(),
+			user_id: String::from("guest"),
+		};
+		let counts = HashMap::from([(
+			payload(r#"{"a":1}"#),
+			Counts {
+				count: 3,
+				producers: vec![(producer("billing"), 2), (producer("orders"), 1)],
+				queues: vec![(String::from("invoices"), 2)],
+			},
+		)]);
+		insert_counts(&pool, &counts, None).await.unwrap();
+		insert_counts(&pool, &counts, None).await.unwrap();
+
+		let rows: Vec<(String, String, i64)> =
+			sqlx::query_as("select app_id, user_id, count from entity_producer order by app_id")
+				.fetch_all(&pool)
+				.await
+				.unwrap();
+		assert_eq!(
+			rows,
+			vec![
+				(String::from("billing"), String::from("guest"), 4),
+				(String::from("orders"), String::from("guest"), 2)
+			]
+		);
+
+		let queues: Vec<(String, i64)> = sqlx::query_as("select queue, count from entity_queue")
+			.fetch_all(&pool)
+			.await
+			.unwrap();
+		assert_eq!(queues, vec![(String::from("invoices"), 4)]);
+
+		sqlx::query("delete from entity")
+			.execute(&pool)
+			.await
+			.unwrap();
+		let left: i64 = sqlx::query_scalar(
+			"select (select count(*) from entity_producer) + (select count(*) from entity_queue)",
+		)
+		.fetch_one(&pool)
+		.await
+		.unwrap();
+		assert_eq!(left, 0);
+	}
+
+	#[tokio::test]
+	async fn downsampling() {
+		let pool = connect("sqlite::memory:").await;
+		migrate(&pool).await;
+		let counts = HashMap::from([(payload(r#"{"a":1}"#), Counts::from(1))]);
+		insert_counts(&pool, &counts, Some("minute")).await.unwrap();
+		sqlx::query(
+			r#"
+			insert into entity_counts
+			select
+				id, key_digest, vhost, exchange, discriminator, cloudevent_type, cloudevent_source,
+				envelope_id, bucket, 1
+			from entity, (
+				select '2020-01-01T10:01:00Z' as bucket
+				union select '2020-01-01T11:02:00Z'
+				union select '2020-01-02T00:00:00Z'
+			)
+		"#,
+		)
+		.execute(&pool)
+		.await
+		.unwrap();
+
+		assert_eq!(pool.downsample(24, "day").await.unwrap(), 1);
+		let buckets: Vec<(String, i64)> =
+			sqlx::query_as("select bucket, count from entity_counts order by bucket")
+				.fetch_all(&pool)
+				.await
+				.unwrap();
+		assert_eq!(buckets.len(), 3);
+		assert_eq!(buckets[0], (String::from("2020-01-01T00:00:00Z"), 2));
+		assert_eq!(buckets[1], (String::from("2020-01-02T00:00:00Z"), 1));
+	}
+
+	#[tokio::test]
+	async fn pruning() {
+		let pool = connect("sqlite::memory:").await;
+		migrate(&pool).await;
+		let counts = HashMap::from([
+			(payload(r#"{"a":1}"#), Counts::from(1)),
+			(payload(r#"{"b":1}"#), Counts::from(1)),
+			(payload(r#"{"c":1}"#), Counts::from(1)),
+		]);
+		insert_counts(&pool, &counts, Some("hour")).await.unwrap();
+		let mut other = payload(r#"{"a":1}"#);
+		other.exchange = String::from("robserver.other");
+		insert_counts(
+			&pool,
+			&HashMap::from([(other, Counts::from(1))]),
+			Some("hour"),
+		)
+		.await
+		.unwrap();
+		sqlx::query("update entity set last_seen_at = '2020-01-01T00:00:00.000Z'")
+			.execute(&pool)
+			.await
+			.unwrap();
+		sqlx::query("update entity_counts set bucket = '2020-01-01T00:00:00Z'")
+			.execute(&pool)
+			.await
+			.unwrap();
+		insert_counts(
+			&pool,
+			&HashMap::from([(payload(r#"{"a":1}"#), Counts::from(1))]),
+			Some("hour"),
+		)
+		.await
+		.unwrap();
+		sqlx::query(
+			"insert into entity_cluster (vhost, exchange, cluster_id, key_paths, shapes, count)
+			values ('/', ?1, 'gone', '[]', 1, 1)",
+		)
+		.bind(EXCHANGE)
+		.execute(&pool)
+		.await
+		.unwrap();
+
+		let retention: Retention = "24,robserver.other:0".parse().unwrap();
+		let prunable = pool.prunable(&retention).await.unwrap();
+		assert_eq!(prunable.len(), 1);
+		assert_eq!(prunable[0].exchange, EXCHANGE);
+		assert_eq!(prunable[0].shapes, 2);
+		assert_eq!(prunable[0].buckets, 3);
+
+		assert_eq!(pool.prune_shapes(&retention, 1).await.unwrap(), 1);
+		assert_eq!(pool.prune_shapes(&retention, 1).await.unwrap(), 1);
+		assert_eq!(pool.prune_shapes(&retention, 1).await.unwrap(), 0);
+		assert_eq!(pool.prune_buckets(&retention, 10).await.unwrap(), 1);
+		assert_eq!(pool.prune_clusters().await.unwrap(), 1);
+		assert!(pool.prunable(&retention).await.unwrap().is_empty());
+
+		let shapes: Vec<(String, i64)> = sqlx::query_as(
+			"select e.exchange, count(c.bucket) from entity e
+			left join entity_counts c using (id, key_digest, vhost, exchange)
+			group by 1 order by 1",
+		)
+		.fetch_all(&pool)
+		.await
+		.unwrap();
+		assert_eq!(
+			shapes,
+			vec![
+				(String::from("robserver.other"), 1),
+				(String::from(EXCHANGE), 1)
+			]
+		);
+	}
+}
@@ -1,33 +1,294 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
 use serde_json::Value;
-use tracing::debug;
-
-pub fn hash_object<T: Hasher>(obj: &Value, s: T) -> T {
-	let mut state: T = s;
-	if let Value::Object(x) = obj {
-		'>'.hash(&mut state);
-		for (key, value) in x.iter() {
-			debug!("< {key}: {value}");
-			key.hash(&mut state);
-			state = hash_object(value, state);
+use tracing::trace;
+
+const PATH_SEPARATOR: char = '.';
+const SCOPE_SEPARATOR: char = ':';
+const ROUTING_KEY_SEPARATOR: char = '/';
+const WILDCARD: &str = "*";
+/// Appended to key paths of string values holding JSON.
+const EMBEDDED_JSON_MARKER: &str = "#json";
+/// Appended to key paths of objects left out for being too deep or having too many keys.
+const TRUNCATED_MARKER: &str = "#truncated";
+
+/// Key path, optionally scoped to an exchange and a routing key.
+///
+/// Written as `[exchange[/routing_key]:]path`, where `path` is a `.`-separated list of keys and
+/// `*` matches any single key, e.g. `debug`, `_meta.*` or `orders/order.created:payload.trace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRule {
+	pub exchange: Option<String>,
+	pub routing_key: Option<String>,
+	pub path: Vec<String>,
+}
+
+impl PathRule {
+	pub fn applies_to(&self, exchange: &str, routing_key: &str) -> bool {
+		!matches!(&self.exchange, Some(ex) if ex != exchange)
+			&& !matches!(&self.routing_key, Some(rk) if rk != routing_key)
+	}
+
+	fn matches(&self, path: &[String]) -> bool {
+		self.path.len() == path.len()
+			&& self
+				.path
+				.iter()
+				.zip(path)
+				.all(|(rule, key)| rule == WILDCARD || rule == key)
+	}
+
+	/// Value at the path in `obj`. With wildcards the first match in key order is returned.
+	pub fn lookup<'v>(&self, obj: &'v Value) -> Option<&'v Value> {
+		lookup(obj, &self.path)
+	}
+}
+
+fn lookup<'v>(obj: &'v Value, path: &[String]) -> Option<&'v Value> {
+	let Some((key, rest)) = path.split_first() else {
+		return Some(obj);
+	};
+	let Value::Object(x) = obj else {
+		return None;
+	};
+	if key == WILDCARD {
+		x.values().find_map(|value| lookup(value, rest))
+	} else {
+		x.get(key).and_then(|value| lookup(value, rest))
+	}
+}
+
+impl FromStr for PathRule {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (scope, path) = match s.split_once(SCOPE_SEPARATOR) {
+			Some((scope, path)) => (Some(scope), path),
+			None => (None, s),
+		};
+		let (exchange, routing_key) = match scope {
+			Some(scope) => match scope.split_once(ROUTING_KEY_SEPARATOR) {
+				Some((ex, rk)) => (Some(ex.to_string()), Some(rk.to_string())),
+				None => (Some(scope.to_string()), None),
+			},
+			None => (None, None),
+		};
+		let path: Vec<String> = path.split(PATH_SEPARATOR).map(str::to_string).collect();
+		if path.iter().any(String::is_empty) {
+			return Err(format!("invalid key path in rule: {s:?}"));
 		}
+
+		Ok(PathRule {
+			exchange,
+			routing_key,
+			path,
+		})
+	}
+}
+
+impl fmt::Display for PathRule {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (&self.exchange, &self.routing_key) {
+			(Some(ex), Some(rk)) => write!(f, "{ex}{ROUTING_KEY_SEPARATOR}{rk}{SCOPE_SEPARATOR}")?,
+			(Some(ex), None) => write!(f, "{ex}{SCOPE_SEPARATOR}")?,
+			_ => {}
+		}
+		write!(f, "{}", self.path.join(&PATH_SEPARATOR.to_string()))
+	}
+}
+
+/// Shape of a JSON value: its fingerprint and the key paths it consists of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shape {
+	pub id: u64,
+	/// `.`-separated paths of all the keys, depth-first with sibling keys sorted.
+	pub key_paths: Vec<String>,
+	/// Fingerprint with key names normalized, `0` unless [`Traversal::normalize_keys`] or
+	/// [`Traversal::naming_variants`] is set.
+	pub normalized_id: u64,
+	/// Whether some of the structure was left out due to [`Traversal`] limits.
+	pub truncated: bool,
+}
+
+/// How a JSON value is traversed to determine its shape.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Traversal<'r> {
+	/// Keys matching any of the rules, together with everything nested under them, don't
+	/// contribute to the shape.
+	pub ignore: &'r [&'r PathRule],
+	/// String values up to that many bytes holding a JSON object or an array are parsed and
+	/// treated as nested structure. `0` disables that.
+	pub embedded_json_max_size: usize,
+	/// Fold key case and `_`/`-` separators, so `userId`, `user_id` and `user-id` are the same.
+	pub normalize_keys: bool,
+	/// Also determine [`Shape::normalized_id`] when keys aren't normalized, at the cost of a second
+	/// traversal.
+	pub naming_variants: bool,
+	/// Objects nested deeper than that are summarized as truncated. `0` means no limit.
+	pub max_depth: usize,
+	/// Keys of an object beyond that many are summarized as truncated. `0` means no limit.
+	pub max_keys: usize,
+}
+
+pub fn shape_of(obj: &Value, traversal: Traversal) -> Shape {
+	let mut walker = Walker::new(traversal, traversal.normalize_keys);
+	let id = walker.hash(obj, DefaultHasher::new()).finish();
+	let normalized_id = if traversal.normalize_keys {
+		id
+	} else if traversal.naming_variants {
+		Walker::new(traversal, true)
+			.hash(obj, DefaultHasher::new())
+			.finish()
+	} else {
+		0
+	};
+	Shape {
+		id,
+		key_paths: walker.key_paths,
+		normalized_id,
+		truncated: walker.truncated,
+	}
+}
+
+fn normalize_key(key: &str) -> String {
+	key.chars()
+		.filter(|c| *c != '_' && *c != '-')
+		.flat_map(char::to_lowercase)
+		.collect()
+}
+
+struct Walker<'r> {
+	traversal: Traversal<'r>,
+	normalize: bool,
+	/// Keys as they are in the value, for matching ignore rules.
+	path: Vec<String>,
+	/// Keys as they make up the shape.
+	names: Vec<String>,
+	key_paths: Vec<String>,
+	truncated: bool,
+}
+
+impl<'r> Walker<'r> {
+	fn new(traversal: Traversal<'r>, normalize: bool) -> Self {
+		Walker {
+			traversal,
+			normalize,
+			path: Vec::new(),
+			names: Vec::new(),
+			key_paths: Vec::new(),
+			truncated: false,
+		}
+	}
+
+	fn hash<T: Hasher>(&mut self, obj: &Value, s: T) -> T {
+		let mut state: T = s;
+		if let Value::Object(x) = obj {
+			'>'.hash(&mut state);
+			let mut entries: Vec<(&String, String, &Value)> = x
+				.iter()
+				.map(|(key, value)| {
+					let name = if self.normalize {
+						normalize_key(key)
+					} else {
+						key.clone()
+					};
+					(key, name, value)
+				})
+				.collect();
+			if self.normalize {
+				entries.sort_by(|a, b| a.1.cmp(&b.1));
+			}
+			let mut kept = 0;
+			for (key, name, value) in entries {
+				self.path.push(key.clone());
+				let is_ignored = self
+					.traversal
+					.ignore
+					.iter()
+					.any(|rule| rule.matches(&self.path));
+				self.path.pop();
+				if is_ignored {
+					trace!("< {key}: ignored");
+					continue;
+				}
+				if self.traversal.max_keys != 0 && kept == self.traversal.max_keys {
+					trace!("< {key}: too many keys");
+					'!'.hash(&mut state);
+					self.names.push(String::new());
+					self.truncate(self.names.join(&PATH_SEPARATOR.to_string()));
+					self.names.pop();
+					break;
+				}
+				kept += 1;
+
+				trace!("< {key}");
+				name.hash(&mut state);
+				self.path.push(key.clone());
+				self.names.push(name);
+				let key_path = self.names.join(&PATH_SEPARATOR.to_string());
+				if self.traversal.max_depth != 0 && self.names.len() >= self.traversal.max_depth {
+					if value.is_object() {
+						trace!("< {key}: too deep");
+						'!'.hash(&mut state);
+						self.truncate(key_path);
+					} else {
+						self.key_paths.push(key_path);
+					}
+				} else {
+					match self.embedded_json(value) {
+						Some(embedded) => {
+							self.key_paths.push(key_path + EMBEDDED_JSON_MARKER);
+							'$'.hash(&mut state);
+							state = self.hash(&embedded, state);
+						}
+						None => {
+							self.key_paths.push(key_path);
+							state = self.hash(value, state);
+						}
+					}
+				}
+				self.names.pop();
+				self.path.pop();
+			}
+		}
+		state
+	}
+
+	fn truncate(&mut self, key_path: String) {
+		self.truncated = true;
+		self.key_paths.push(key_path + TRUNCATED_MARKER);
+	}
+
+	fn embedded_json(&self, value: &Value) -> Option<Value> {
+		let Value::String(s) = value else {
+			return None;
+		};
+		if s.len() > self.traversal.embedded_json_max_size
+			|| !(s.trim_start().starts_with('{') || s.trim_start().starts_with('['))
+		{
+			return None;
+		}
+		serde_json::from_str(s)
+			.ok()
+			.filter(|embedded: &Value| embedded.is_object() || embedded.is_array())
 	}
-	state
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
 
-	use std::collections::hash_map::DefaultHasher;
 	use std::{assert_ne, hint::black_box};
 
+	use tracing::debug;
+
 	fn str_to_payload_hash(input: &str) -> u64 {
 		debug!("hashing:\n{}", input);
-		let s = DefaultHasher::new();
 		let parsed: Value = serde_json::from_str(input).unwrap();
-		hash_object(&parsed, s).finish()
+		shape_of(&parsed, Traversal::default()).id
 	}
 
 	const DATA: &str = r#"
@@ -117,6 +378,310 @@ mod tests {
 		);
 	}
 
+	fn rules(rules: &[&str]) -> Vec<PathRule> {
+		rules.iter().map(|r| r.parse().unwrap()).collect()
+	}
+
+	fn str_to_payload_hash_ignoring(input: &str, ignore: &[PathRule]) -> u64 {
+		let parsed: Value = serde_json::from_str(input).unwrap();
+		let ignore: Vec<&PathRule> = ignore.iter().collect();
+		shape_of(
+			&parsed,
+			Traversal {
+				ignore: &ignore,
+				..Traversal::default()
+			},
+		)
+		.id
+	}
+
+	#[test]
+	fn path_rule_parse() {
+		assert_eq!(
+			"_meta.*".parse::<PathRule>().unwrap(),
+			PathRule {
+				exchange: None,
+				routing_key: None,
+				path: vec!["_meta".into(), "*".into()],
+			}
+		);
+		assert_eq!(
+			"orders/order.created:payload.trace"
+				.parse::<PathRule>()
+				.unwrap(),
+			PathRule {
+				exchange: Some("orders".into()),
+				routing_key: Some("order.created".into()),
+				path: vec!["payload".into(), "trace".into()],
+			}
+		);
+		assert!("a..b".parse::<PathRule>().is_err());
+		assert!("orders:".parse::<PathRule>().is_err());
+	}
+
+	#[test]
+	fn path_rule_display() {
+		for rule in ["debug", "_meta.*", "orders:a.b", "orders/order.created:a"] {
+			assert_eq!(rule.parse::<PathRule>().unwrap().to_string(), rule);
+		}
+	}
+
+	#[test]
+	fn path_rule_scope() {
+		let rule: PathRule = "orders/order.created:debug".parse().unwrap();
+		assert!(rule.applies_to("orders", "order.created"));
+		assert!(!rule.applies_to("orders", "order.cancelled"));
+		assert!(!rule.applies_to("users", "order.created"));
+
+		let rule: PathRule = "orders:debug".parse().unwrap();
+		assert!(rule.applies_to("orders", "order.cancelled"));
+		assert!(!rule.applies_to("users", "order.created"));
+	}
+
+	#[test]
+	fn path_rule_lookup() {
+		let parsed: Value = serde_json::from_str(DATA).unwrap();
+		let lookup = |rule: &str| rule.parse::<PathRule>().unwrap().lookup(&parsed).cloned();
+
+		assert_eq!(lookup("age"), Some(Value::from(43)));
+		assert_eq!(lookup("deep.deep.object.b"), Some(Value::from("b_value")));
+		assert_eq!(lookup("deep.*.*.a"), Some(Value::from(3123)));
+		assert_eq!(lookup("deep.missing"), None);
+		assert_eq!(lookup("age.value"), None);
+	}
+
+	#[test]
+	fn ignore_nothing_eq() {
+		assert_eq!(
+			str_to_payload_hash_ignoring(DATA, &rules(&["missing", "deep.missing"])),
+			str_to_payload_hash(DATA)
+		);
+	}
+
+	#[test]
+	fn ignore_keys() {
+		assert_eq!(
+			str_to_payload_hash_ignoring(DATA_CHANGED, &rules(&["firstName"])),
+			str_to_payload_hash_ignoring(DATA, &rules(&["name"]))
+		);
+		assert_eq!(
+			str_to_payload_hash_ignoring(DATA_DEEPER_PROP, &rules(&["deep.deep.object.c"])),
+			str_to_payload_hash(DATA)
+		);
+		assert_eq!(
+			str_to_payload_hash_ignoring(DATA_A, &rules(&["b", "c"])),
+			str_to_payload_hash_ignoring(DATA_B, &rules(&["b"]))
+		);
+	}
+
+	#[test]
+	fn ignore_wildcard() {
+		assert_eq!(
+			str_to_payload_hash_ignoring(DATA_DEEPER_PROP, &rules(&["deep.*.object.c"])),
+			str_to_payload_hash(DATA)
+		);
+		assert_ne!(
+			str_to_payload_hash_ignoring(DATA_DEEPER_PROP, &rules(&["*.c"])),
+			str_to_payload_hash(DATA)
+		);
+	}
+
+	#[test]
+	fn shape_key_paths() {
+		let shape = shape_of(&serde_json::from_str(DATA).unwrap(), Traversal::default());
+		assert_eq!(shape.id, str_to_payload_hash(DATA));
+		assert_eq!(
+			shape.key_paths,
+			vec![
+				"a",
+				"age",
+				"deep",
+				"deep.deep",
+				"deep.deep.object",
+				"deep.deep.object.a",
+				"deep.deep.object.b",
+				"name",
+				"phones",
+			]
+		);
+		assert_eq!(
+			shape,
+			shape_of(
+				&serde_json::from_str(DATA_REORDERED).unwrap(),
+				Traversal::default()
+			)
+		);
+
+		let ignore = rules(&["deep.deep"]);
+		let ignore: Vec<&PathRule> = ignore.iter().collect();
+		let shape = shape_of(
+			&serde_json::from_str(DATA).unwrap(),
+			Traversal {
+				ignore: &ignore,
+				..Traversal::default()
+			},
+		);
+		assert_eq!(shape.key_paths, vec!["a", "age", "deep", "name", "phones"]);
+	}
+
+	#[test]
+	fn embedded_json() {
+		let shape = |input: &str, embedded_json_max_size| {
+			shape_of(
+				&serde_json::from_str(input).unwrap(),
+				Traversal {
+					embedded_json_max_size,
+					..Traversal::default()
+				},
+			)
+		};
+		const EMBEDDED: &str = r#"{ "body": "{\"a\": 1, \"b\": { \"c\": 2 }}", "list": "[1]" }"#;
+		const EMBEDDED_OTHER: &str =
+			r#"{ "body": "{\"a\": 2, \"b\": { \"c\": 3 }}", "list": " [2]" }"#;
+		const NESTED: &str = r#"{ "body": { "a": 1, "b": { "c": 2 } }, "list": [1] }"#;
+		const STRINGS: &str = r#"{ "body": "{ a: 1 }", "list": "[" }"#;
+
+		assert_eq!(shape(EMBEDDED, 0), shape(STRINGS, 0));
+		assert_eq!(shape(EMBEDDED, 0).key_paths, vec!["body", "list"]);
+
+		assert_eq!(
+			shape(EMBEDDED, 100).key_paths,
+			vec!["body#json", "body.a", "body.b", "body.b.c", "list#json"]
+		);
+		assert_eq!(shape(EMBEDDED, 100), shape(EMBEDDED_OTHER, 100));
+		assert_ne!(shape(EMBEDDED, 100).id, shape(STRINGS, 100).id);
+		assert_ne!(shape(EMBEDDED, 100).id, shape(NESTED, 100).id);
+		assert_eq!(shape(STRINGS, 100), shape(STRINGS, 0));
+
+		assert_eq!(shape(EMBEDDED, 10).key_paths, vec!["body", "list#json"]);
+	}
+
+	#[test]
+	fn normalized_keys() {
+		let shape = |input: &str, normalize_keys| {
+			shape_of(
+				&serde_json::from_str(input).unwrap(),
+				Traversal {
+					normalize_keys,
+					naming_variants: true,
+					..Traversal::default()
+				},
+			)
+		};
+		const CAMEL: &str = r#"{ "userId": 1, "userName": { "firstName": "a" } }"#;
+		const SNAKE: &str = r#"{ "user_name": { "first_name": "a" }, "user_id": 1 }"#;
+		const KEBAB: &str = r#"{ "User-Id": 1, "user-name": { "FIRST-NAME": "a" } }"#;
+		const OTHER: &str = r#"{ "userId": 1, "userName": { "lastName": "a" } }"#;
+
+		assert_ne!(shape(CAMEL, false).id, shape(SNAKE, false).id);
+		assert_eq!(
+			shape(CAMEL, false).normalized_id,
+			shape(SNAKE, false).normalized_id
+		);
+		assert_eq!(
+			shape(CAMEL, false).normalized_id,
+			shape(KEBAB, false).normalized_id
+		);
+		assert_ne!(
+			shape(CAMEL, false).normalized_id,
+			shape(OTHER, false).normalized_id
+		);
+
+		assert_eq!(shape(CAMEL, true), shape(SNAKE, true));
+		assert_eq!(shape(CAMEL, true), shape(KEBAB, true));
+		assert_eq!(shape(CAMEL, true).id, shape(CAMEL, false).normalized_id);
+		assert_eq!(
+			shape_of(&serde_json::from_str(CAMEL).unwrap(), Traversal::default()).normalized_id,
+			0
+		);
+		assert_eq!(
+			shape(SNAKE, true).key_paths,
+			vec!["userid", "username", "username.firstname"]
+		);
+		assert_eq!(
+			shape(SNAKE, false).key_paths,
+			vec!["user_id", "user_name", "user_name.first_name"]
+		);
+	}
+
+	#[test]
+	fn max_depth() {
+		let shape = |input: &str, max_depth| {
+			shape_of(
+				&serde_json::from_str(input).unwrap(),
+				Traversal {
+					max_depth,
+					..Traversal::default()
+				},
+			)
+		};
+
+		assert_eq!(shape(DATA, 0).id, str_to_payload_hash(DATA));
+		assert!(!shape(DATA, 0).truncated);
+		assert_eq!(shape(DATA, 4), shape(DATA, 0));
+
+		let truncated = shape(DATA, 2);
+		assert!(truncated.truncated);
+		assert_ne!(truncated.id, str_to_payload_hash(DATA));
+		assert_eq!(truncated, shape(DATA_DEEPER_PROP, 2));
+		assert_eq!(
+			truncated.key_paths,
+			vec!["a", "age", "deep", "deep.deep#truncated", "name", "phones"]
+		);
+
+		assert_ne!(shape(DATA_A, 1).id, shape(DATA_B, 1).id);
+		assert_eq!(shape(DATA_B, 1).key_paths, vec!["a", "b#truncated"]);
+	}
+
+	#[test]
+	fn max_keys() {
+		let shape = |input: &str, max_keys| {
+			shape_of(
+				&serde_json::from_str(input).unwrap(),
+				Traversal {
+					max_keys,
+					..Traversal::default()
+				},
+			)
+		};
+
+		assert_eq!(shape(DATA_A, 3), shape(DATA_A, 0));
+		assert!(!shape(DATA_A, 3).truncated);
+
+		let truncated = shape(DATA_A, 2);
+		assert!(truncated.truncated);
+		assert_eq!(truncated.key_paths, vec!["a", "b", "#truncated"]);
+		assert_ne!(truncated.id, str_to_payload_hash(DATA_A));
+		assert_eq!(
+			shape(DATA_DEEPER_PROP, 2).key_paths,
+			vec!["a", "age", "#truncated"]
+		);
+		assert_eq!(
+			shape(r#"{ "a": { "b": 1, "c": 2, "d": 3 } }"#, 2).key_paths,
+			vec!["a", "a.b", "a.c", "a.#truncated"]
+		);
+	}
+
+	#[test]
+	fn deeply_nested() {
+		let depth = 1_000;
+		let mut value = Value::Null;
+		for _ in 0..depth {
+			let mut object = serde_json::Map::new();
+			object.insert(String::from("a"), value);
+			value = Value::Object(object);
+		}
+		let shape = shape_of(
+			&value,
+			Traversal {
+				max_depth: 32,
+				..Traversal::default()
+			},
+		);
+		assert!(shape.truncated);
+		assert_eq!(shape.key_paths.len(), 32);
+	}
+
 	#[ignore = "ignore benchmarks for faster test runs"]
 	#[test]
 	fn bench() {
@@ -1,4 +1,6 @@
 mod amqp;
+mod cloudevents;
+mod cluster;
 mod config;
 mod db;
 mod hash;
@@ -12,10 +14,27 @@ use payload::Payload;
 async fn main() {
 	config::init();
 
+	if config::get_migrate_only() {
+		db::migrate().await;
+		return;
+	}
+
 	let (payload_tx, payload_rx) = mpsc::channel::<Payload>(config::get_buffer_size());
+	let (ack_tx, ack_rx) = mpsc::unbounded_channel::<u64>();
+
+	let database = db::connect().await;
+	if config::cluster::get_interval() > 0 {
+		tokio::spawn(db::clusterer(database.clone()));
+	}
+	if config::history::get_bucket().is_some() && config::history::get_downsample_after() > 0 {
+		tokio::spawn(db::downsampler(database.clone()));
+	}
+	if config::retention::get_retention().is_enabled() {
+		tokio::spawn(db::pruner(database.clone()));
+	}
 
-	let listener = amqp::listen_messages(payload_tx);
-	let consumer = db::consumer(payload_rx);
+	let listener = amqp::listen_messages(payload_tx, ack_rx);
+	let consumer = db::consumer(database, payload_rx, ack_tx);
 
 	tokio::select!(
 		_ = listener => {}
@@ -1,59 +1,330 @@
-use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::debug;
 
-use crate::hash::hash_object;
+use crate::cloudevents::{self, Envelope};
+use crate::hash::{shape_of, PathRule, Traversal};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Data {
 	Json(Value),
 	Raw(Vec<u8>),
 }
 
-#[derive(Debug, Clone)]
+impl Data {
+	fn parse(data: Vec<u8>) -> Data {
+		match serde_json::from_slice(&data) {
+			Ok(json) => Data::Json(json),
+			Err(_) => Data::Raw(data),
+		}
+	}
+}
+
+/// Non-blank lines of a body, without line endings.
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+	data.split(|b| *b == b'\n')
+		.map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+		.filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+		.collect()
+}
+
+/// Settings for how the shape of a payload is determined.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+	pub ignore: Vec<PathRule>,
+	/// Paths whose value tells apart different kinds of payloads. The first one found is used.
+	pub discriminators: Vec<PathRule>,
+	/// Exchanges carrying CloudEvents.
+	pub cloudevents: Vec<String>,
+	/// See [`Traversal::embedded_json_max_size`].
+	pub embedded_json_max_size: usize,
+	/// See [`Traversal::normalize_keys`].
+	pub normalize_keys: bool,
+	/// See [`Traversal::naming_variants`].
+	pub naming_variants: bool,
+	/// See [`Traversal::max_depth`].
+	pub max_depth: usize,
+	/// See [`Traversal::max_keys`].
+	pub max_keys: usize,
+}
+
+const NDJSON_CONTENT_TYPES: [&str; 3] = [
+	"application/x-ndjson",
+	"application/ndjson",
+	"application/jsonl",
+];
+
+/// Who published a message, as far as its properties tell. Empty strings when not set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Producer {
+	/// `app_id` property, as set by the publisher.
+	pub app_id: String,
+	/// `user_id` property, validated by RabbitMQ to be the user publishing the message.
+	pub user_id: String,
+}
+
+/// Message metadata besides the body.
+#[derive(Debug, Clone, Default)]
+pub struct Properties {
+	/// MIME type without parameters.
+	pub content_type: Option<String>,
+	/// Headers with string values.
+	pub headers: BTreeMap<String, String>,
+	pub producer: Producer,
+}
+
+/// Serializable to be spooled to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Payload {
 	pub content: Data,
 	pub id: u64,
 	pub vhost: String,
 	pub exchange: String,
 	pub routing_key: String,
+	/// Value found at one of the discriminator paths or empty.
+	pub discriminator: String,
+	/// CloudEvents envelope, in which case `id` is the shape of the `data` member only.
+	pub envelope: Option<Envelope>,
+	/// Key paths the shape consists of. Compared to tell apart distinct shapes with the same `id`.
+	pub key_paths: Vec<String>,
+	/// Shape with key names normalized, for finding shapes differing only by naming convention.
+	pub normalized_id: u64,
+	/// Whether key names were normalized for `id`.
+	pub normalized_keys: bool,
+	/// Whether the payload exceeded depth or width limits and only part of it makes up the shape.
+	pub truncated: bool,
+	/// Ignore rules that were in effect when the shape was computed.
+	pub ignore_rules: Vec<String>,
+	/// Taken out when aggregating, counted per shape.
+	#[serde(skip)]
+	pub producer: Producer,
+	/// Queues other than robserver's the message was routed to. Taken out when aggregating,
+	/// counted per shape.
+	#[serde(skip)]
+	pub queues: Vec<String>,
+	/// Delivery to acknowledge, up to and including, once the payload's count is committed. Only
+	/// set on the last payload of a message when acknowledging after commit.
+	#[serde(skip)]
+	pub delivery_tag: Option<u64>,
 }
 
 impl Payload {
+	#[cfg(test)]
 	pub fn new(data: Vec<u8>, vhost: String, exchange: String, routing_key: String) -> Payload {
-		let Ok(json) = serde_json::from_slice(&data) else {
-			return Payload {
-				content: Data::Raw(data),
-				id: 0,
-				vhost,
-				exchange,
-				routing_key,
-			};
+		Payload::with_options(
+			data,
+			vhost,
+			exchange,
+			routing_key,
+			&Properties::default(),
+			&Options::default(),
+		)
+	}
+
+	/// Payloads in a message body. Newline-delimited JSON, whether declared by the content type or
+	/// detected from a body of several lines that each parse as JSON, results in a payload per line.
+	pub fn decode(
+		data: Vec<u8>,
+		vhost: String,
+		exchange: String,
+		routing_key: String,
+		properties: &Properties,
+		options: &Options,
+	) -> Vec<Payload> {
+		let is_ndjson = properties
+			.content_type
+			.as_deref()
+			.is_some_and(|content_type| NDJSON_CONTENT_TYPES.contains(&content_type));
+		let contents: Vec<Data> = if is_ndjson {
+			lines(&data)
+				.into_iter()
+				.map(|line| Data::parse(line.to_vec()))
+				.collect()
+		} else {
+			match Data::parse(data) {
+				// Only bodies that aren't a single JSON value are checked for a value per line
+				Data::Raw(data) => {
+					let lines = lines(&data);
+					let values = if lines.len() > 1 {
+						lines
+							.iter()
+							.map(|line| serde_json::from_slice(line).map(Data::Json))
+							.collect::<Result<Vec<Data>, _>>()
+							.ok()
+					} else {
+						None
+					};
+					values.unwrap_or_else(|| vec![Data::Raw(data)])
+				}
+				json => vec![json],
+			}
+		};
+		if is_ndjson || contents.len() > 1 {
+			debug!(lines = contents.len(), exchange, "Decoding NDJSON");
+		}
+
+		contents
+			.into_iter()
+			.map(|content| {
+				Payload::from_content(
+					content,
+					vhost.clone(),
+					exchange.clone(),
+					routing_key.clone(),
+					properties,
+					options,
+				)
+			})
+			.collect()
+	}
+
+	#[cfg(test)]
+	pub fn with_options(
+		data: Vec<u8>,
+		vhost: String,
+		exchange: String,
+		routing_key: String,
+		properties: &Properties,
+		options: &Options,
+	) -> Payload {
+		Payload::from_content(
+			Data::parse(data),
+			vhost,
+			exchange,
+			routing_key,
+			properties,
+			options,
+		)
+	}
+
+	fn from_content(
+		content: Data,
+		vhost: String,
+		exchange: String,
+		routing_key: String,
+		properties: &Properties,
+		options: &Options,
+	) -> Payload {
+		let is_cloudevents = options.cloudevents.contains(&exchange);
+		let mut envelope = None;
+		if is_cloudevents {
+			match cloudevents::from_headers(&properties.headers) {
+				Some(Ok(binary)) => envelope = Some(binary),
+				Some(Err(error)) => debug!(error, exchange, "Invalid CloudEvents headers"),
+				None => {}
+			}
+		}
+
+		let json = match content {
+			Data::Json(json) => json,
+			Data::Raw(data) => {
+				return Payload {
+					content: Data::Raw(data),
+					id: 0,
+					vhost,
+					exchange,
+					routing_key,
+					discriminator: String::new(),
+					envelope,
+					key_paths: Vec::new(),
+					normalized_id: 0,
+					normalized_keys: options.normalize_keys,
+					truncated: false,
+					ignore_rules: Vec::new(),
+					producer: properties.producer.clone(),
+					queues: Vec::new(),
+					delivery_tag: None,
+				};
+			}
+		};
+
+		let mut body = Some(&json);
+		if is_cloudevents && envelope.is_none() {
+			match cloudevents::from_structured(&json) {
+				Ok((structured, data)) => {
+					envelope = Some(structured);
+					body = data;
+				}
+				Err(error) => debug!(error, exchange, "Invalid CloudEvent"),
+			}
+		}
+
+		let ignore: Vec<&PathRule> = options
+			.ignore
+			.iter()
+			.filter(|rule| rule.applies_to(&exchange, &routing_key))
+			.collect();
+		let (discriminator, shape) = match body {
+			Some(body) => (
+				options
+					.discriminators
+					.iter()
+					.filter(|rule| rule.applies_to(&exchange, &routing_key))
+					.find_map(|rule| rule.lookup(body).and_then(discriminator_value))
+					.unwrap_or_default(),
+				shape_of(
+					body,
+					Traversal {
+						ignore: &ignore,
+						embedded_json_max_size: options.embedded_json_max_size,
+						normalize_keys: options.normalize_keys,
+						naming_variants: options.naming_variants,
+						max_depth: options.max_depth,
+						max_keys: options.max_keys,
+					},
+				),
+			),
+			None => (String::new(), shape_of(&Value::Null, Traversal::default())),
 		};
-		let s = DefaultHasher::new();
-		let id = hash_object(&json, s).finish();
 		Payload {
 			content: Data::Json(json),
-			id,
+			id: shape.id,
 			vhost,
 			exchange,
 			routing_key,
+			discriminator,
+			envelope,
+			key_paths: shape.key_paths,
+			normalized_id: shape.normalized_id,
+			normalized_keys: options.normalize_keys,
+			truncated: shape.truncated,
+			ignore_rules: ignore.iter().map(ToString::to_string).collect(),
+			producer: properties.producer.clone(),
+			queues: Vec::new(),
+			delivery_tag: None,
 		}
 	}
 }
 
+fn discriminator_value(value: &Value) -> Option<String> {
+	match value {
+		Value::String(s) => Some(s.clone()),
+		Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+		_ => None,
+	}
+}
+
 impl Hash for Payload {
 	fn hash<H: Hasher>(&self, state: &mut H) {
 		self.id.hash(state);
 		self.vhost.hash(state);
 		self.exchange.hash(state);
+		self.discriminator.hash(state);
+		self.envelope.hash(state);
 	}
 }
 
 impl PartialEq for Payload {
 	fn eq(&self, other: &Payload) -> bool {
-		self.id == other.id && self.vhost == other.vhost && self.exchange == other.exchange
+		self.id == other.id
+			&& self.vhost == other.vhost
+			&& self.exchange == other.exchange
+			&& self.discriminator == other.discriminator
+			&& self.envelope == other.envelope
+			&& self.key_paths == other.key_paths
 	}
 }
 
@@ -61,6 +332,7 @@ impl Eq for Payload {}
 
 #[cfg(test)]
 mod tests {
+	use std::collections::hash_map::DefaultHasher;
 	use std::collections::{HashMap, HashSet};
 
 	use super::*;
@@ -178,6 +450,325 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn payload_ignore_rules() {
+		let options = Options {
+			ignore: vec![
+				"prop0".parse().unwrap(),
+				"B:prop1".parse().unwrap(),
+				"A/other:foo".parse().unwrap(),
+			],
+			..Options::default()
+		};
+		let p1 = Payload::with_options(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX2),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		let p3 = Payload::with_options(
+			V3.to_vec(),
+			String::from(VHOST1),
+			String::from(EX2),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		assert_eq!(p1, p3);
+		assert_eq!(p1.ignore_rules, vec!["prop0", "B:prop1"]);
+
+		let p3 = Payload::with_options(
+			V3.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		assert_ne!(
+			Payload::with_options(
+				V1.to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&Properties::default(),
+				&options,
+			),
+			p3
+		);
+		assert_eq!(p3.ignore_rules, vec!["prop0"]);
+	}
+
+	#[test]
+	fn payload_discriminator() {
+		let options = Options {
+			discriminators: vec![
+				"A:missing".parse().unwrap(),
+				"A:prop0".parse().unwrap(),
+				"foo".parse().unwrap(),
+			],
+			..Options::default()
+		};
+		let p1 = Payload::with_options(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		let p2 = Payload::with_options(
+			V2.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		assert_eq!(p1.id, p2.id);
+		assert_eq!(p1.discriminator, "10");
+		assert_eq!(p2.discriminator, "13");
+		assert_ne!(p1, p2);
+		assert_ne!(hash(&p1), hash(&p2));
+
+		let p1 = Payload::with_options(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX2),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		let p2 = Payload::with_options(
+			V2.to_vec(),
+			String::from(VHOST1),
+			String::from(EX2),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		assert_eq!(p1.discriminator, "bar");
+		assert_eq!(p1, p2);
+	}
+
+	#[test]
+	fn payload_cloudevents() {
+		let options = Options {
+			cloudevents: vec![String::from(EX1)],
+			..Options::default()
+		};
+		let structured = |data: &str| {
+			Payload::with_options(
+				format!(
+					r#"{{"specversion":"1.0","type":"created","source":"/a","id":"1","data":{data}}}"#
+				)
+				.into_bytes(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&Properties::default(),
+				&options,
+			)
+		};
+		let binary = |data: &[u8], r#type: &str| {
+			let properties = Properties {
+				content_type: None,
+				headers: [
+					("ce_specversion", "1.0"),
+					("ce_type", r#type),
+					("ce_source", "/a"),
+					("ce_id", "1"),
+				]
+				.iter()
+				.map(|(k, v)| (k.to_string(), v.to_string()))
+				.collect(),
+				..Properties::default()
+			};
+			Payload::with_options(
+				data.to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&properties,
+				&options,
+			)
+		};
+		let plain = Payload::new(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+		);
+
+		let p1 = structured(std::str::from_utf8(V1).unwrap());
+		assert_eq!(p1.id, plain.id);
+		assert_eq!(p1.key_paths, plain.key_paths);
+		assert_eq!(p1.envelope.as_ref().unwrap().r#type, "created");
+		assert_eq!(p1.envelope.as_ref().unwrap().source, "/a");
+		assert_eq!(p1, structured(std::str::from_utf8(V2).unwrap()));
+		assert_ne!(p1, structured(std::str::from_utf8(V3).unwrap()));
+		assert_ne!(p1, plain);
+
+		assert_eq!(binary(V1, "created"), p1);
+		assert_ne!(binary(V1, "created"), binary(V1, "deleted"));
+		assert_eq!(binary(INVALID, "created").envelope, p1.envelope);
+
+		let invalid = Payload::with_options(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+			&Properties::default(),
+			&options,
+		);
+		assert_eq!(invalid, plain);
+	}
+
+	#[test]
+	fn payload_embedded_json() {
+		let options = Options {
+			embedded_json_max_size: 1024,
+			..Options::default()
+		};
+		let payload = |data: &str| {
+			Payload::with_options(
+				data.as_bytes().to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&Properties::default(),
+				&options,
+			)
+		};
+
+		let p1 = payload(r#"{"body":"{\"foo\":\"bar\",\"prop0\":10}"}"#);
+		assert_eq!(p1.key_paths, vec!["body#json", "body.foo", "body.prop0"]);
+		assert_eq!(p1, payload(r#"{"body":"{\"foo\":\"baz\",\"prop0\":13}"}"#));
+		assert_ne!(p1, payload(r#"{"body":"{\"foo\":\"bar\",\"prop1\":10}"}"#));
+		assert_ne!(p1, payload(r#"{"body":"foo"}"#));
+	}
+
+	#[test]
+	fn payload_decode_ndjson() {
+		let decode = |data: &str, content_type: Option<&str>| {
+			Payload::decode(
+				data.as_bytes().to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&Properties {
+					content_type: content_type.map(str::to_string),
+					..Properties::default()
+				},
+				&Options::default(),
+			)
+		};
+		let v1 = std::str::from_utf8(V1).unwrap();
+		let v3 = std::str::from_utf8(V3).unwrap();
+
+		let payloads = decode(&format!("{v1}\n{v3}\r\n\n"), None);
+		assert_eq!(payloads.len(), 2);
+		assert_eq!(
+			payloads[0],
+			Payload::new(V1.to_vec(), VHOST1.into(), EX1.into(), RK.into())
+		);
+		assert_eq!(
+			payloads[1],
+			Payload::new(V3.to_vec(), VHOST1.into(), EX1.into(), RK.into())
+		);
+
+		let payloads = decode(&format!("{v1}\nfoo"), Some("application/x-ndjson"));
+		assert_eq!(payloads.len(), 2);
+		assert_eq!(payloads[1].content, Data::Raw("foo".into()));
+
+		assert_eq!(decode(v1, Some("application/x-ndjson")).len(), 1);
+		assert_eq!(decode(&format!("{v1}\nfoo"), None).len(), 1);
+		assert_eq!(decode("{\n\"a\": 1\n}", None).len(), 1);
+		assert_eq!(decode("", None).len(), 1);
+	}
+
+	#[test]
+	fn payload_normalized_keys() {
+		let payload = |data: &str, normalize_keys| {
+			Payload::with_options(
+				data.as_bytes().to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&Properties::default(),
+				&Options {
+					normalize_keys,
+					naming_variants: true,
+					..Options::default()
+				},
+			)
+		};
+
+		let camel = payload(r#"{"fooBar":1}"#, false);
+		let snake = payload(r#"{"foo_bar":1}"#, false);
+		assert_ne!(camel, snake);
+		assert_eq!(camel.normalized_id, snake.normalized_id);
+		assert!(!camel.normalized_keys);
+
+		let camel = payload(r#"{"fooBar":1}"#, true);
+		assert_eq!(camel, payload(r#"{"foo_bar":1}"#, true));
+		assert_eq!(camel.id, snake.normalized_id);
+		assert!(camel.normalized_keys);
+	}
+
+	#[test]
+	fn payload_limits() {
+		let payload = |data: &[u8], max_depth, max_keys| {
+			Payload::with_options(
+				data.to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				&Properties::default(),
+				&Options {
+					max_depth,
+					max_keys,
+					..Options::default()
+				},
+			)
+		};
+
+		assert!(!payload(V1, 1, 2).truncated);
+		assert_eq!(payload(V1, 1, 2), payload(V1, 0, 0));
+
+		let truncated = payload(V1, 0, 1);
+		assert!(truncated.truncated);
+		assert_eq!(truncated, payload(V3, 0, 1));
+		assert!(payload(br#"{"a":{"b":1}}"#, 1, 0).truncated);
+	}
+
+	#[test]
+	fn payload_cmp_colliding_ids() {
+		let p1 = Payload::new(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+		);
+		let mut p3 = Payload::new(
+			V3.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+		);
+		p3.id = p1.id;
+
+		assert_eq!(hash(&p1), hash(&p3));
+		assert_ne!(p1, p3);
+
+		let mut map = HashMap::new();
+		map.insert(p1, 1);
+		map.insert(p3, 1);
+		assert_eq!(map.len(), 2);
+	}
+
 	#[test]
 	fn payload_invalid() {
 		let payload = Payload::new(
@@ -367,4 +958,60 @@ mod tests {
 		);
 		assert_eq!(map.len(), 2);
 	}
+
+	#[test]
+	fn serde_roundtrip() {
+		let json = Payload::new(
+			V1.to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+		);
+		let mut raw = Payload::new(
+			b"not json".to_vec(),
+			String::from(VHOST1),
+			String::from(EX1),
+			String::from(RK),
+		);
+		raw.delivery_tag = Some(1);
+
+		let counts = vec![(json, 1), (raw, 2)];
+		let spooled: Vec<(Payload, usize)> =
+			serde_json::from_slice(&serde_json::to_vec(&counts).unwrap()).unwrap();
+
+		assert_eq!(spooled.len(), 2);
+		for ((original, count), (restored, restored_count)) in counts.iter().zip(&spooled) {
+			assert_eq!(original, restored);
+			assert_eq!(original.content, restored.content);
+			assert_eq!(count, restored_count);
+		}
+		assert_eq!(spooled[1].1, 2);
+		assert_eq!(spooled[1].0.delivery_tag, None);
+	}
+
+	#[test]
+	fn producer() {
+		let properties = Properties {
+			producer: Producer {
+				app_id: String::from("billing"),
+				user_id: String::from("billing-svc"),
+			},
+			..Properties::default()
+		};
+		let payload = |properties: &Properties| {
+			Payload::with_options(
+				V1.to_vec(),
+				String::from(VHOST1),
+				String::from(EX1),
+				String::from(RK),
+				properties,
+				&Options::default(),
+			)
+		};
+
+		let produced = payload(&properties);
+		assert_eq!(produced.producer, properties.producer);
+		// The same shape, whoever publishes it
+		assert_eq!(produced, payload(&Properties::default()));
+	}
 }